@@ -1,9 +1,12 @@
 use std::collections::HashSet;
 
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
 use reqwest::Client;
 use serde_json;
 
-use crate::blockchain::{Block, Chain, Consensus};
+use super::ENCODED_CONTENT_TYPE;
+use crate::blockchain::{Block, BlockHeader, Chain, Consensus, ConsensusMessage};
+use crate::utils::encoding::{Decode, Encode};
 
 // Broadcast a new block to all registered nodes
 pub async fn broadcast_block<C: Consensus>(
@@ -21,32 +24,79 @@ pub async fn broadcast_block<C: Consensus>(
         }
         match client
             .post(format!("{}/block", node))
-            .json(block)
+            .header(CONTENT_TYPE, ENCODED_CONTENT_TYPE)
+            .body(block.encode())
             .send()
             .await
         {
-            Ok(_) => println!("Block broadcast to {} successful", node),
+            Ok(response) if response.status().is_success() => {
+                println!("Block broadcast to {} successful", node)
+            }
+            Ok(response) => eprintln!(
+                "Block #{} refused by {}: {}",
+                block.header.index,
+                node,
+                response.text().await.unwrap_or_default()
+            ),
             Err(e) => eprintln!("Error broadcasting block to {}: {}", node, e),
         }
     }
     Ok(())
 }
 
-// Sync chains with other nodes and adopt the longest valid chain
-pub async fn sync_chain<C: Consensus>(
+// Send a proposal or vote to all registered nodes
+pub async fn broadcast_consensus_message(nodes: &HashSet<String>, message: &ConsensusMessage) {
+    let client = Client::new();
+
+    for node in nodes {
+        match client
+            .post(format!("{}/consensus", node))
+            .header(CONTENT_TYPE, ENCODED_CONTENT_TYPE)
+            .body(message.encode())
+            .send()
+            .await
+        {
+            Ok(response) if response.status().is_success() => {}
+            Ok(response) => eprintln!(
+                "Consensus message refused by {}: {}",
+                node,
+                response.text().await.unwrap_or_default()
+            ),
+            Err(e) => eprintln!("Error sending consensus message to {}: {}", node, e),
+        }
+    }
+}
+
+// Fetch the canonically encoded `path` of another node
+async fn fetch_encoded<T: Decode>(
     node_address: &str,
-) -> Result<Vec<Block<C::Proof>>, reqwest::Error>
-where
-    C::Proof: serde::de::DeserializeOwned,
-{
+    path: &str,
+) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
     let client = Client::new();
 
-    client
-        .get(format!("{}/chain", node_address))
+    let bytes = client
+        .get(format!("{}{}", node_address, path))
+        .header(ACCEPT, ENCODED_CONTENT_TYPE)
         .send()
         .await?
-        .json::<Vec<Block<C::Proof>>>()
-        .await
+        .error_for_status()?
+        .bytes()
+        .await?;
+    Ok(T::decode(&bytes)?)
+}
+
+// Fetch the chain of another node so the heaviest valid chain can be adopted
+pub async fn sync_chain<C: Consensus>(
+    node_address: &str,
+) -> Result<Vec<Block<C::Proof>>, Box<dyn std::error::Error + Send + Sync>> {
+    fetch_encoded(node_address, "/chain").await
+}
+
+// Fetch the block headers of another node, enough to weigh its chain
+pub async fn sync_headers<C: Consensus>(
+    node_address: &str,
+) -> Result<Vec<BlockHeader<C::Proof>>, Box<dyn std::error::Error + Send + Sync>> {
+    fetch_encoded(node_address, "/headers").await
 }
 
 pub async fn broadcast_node_registration<C: Consensus>(
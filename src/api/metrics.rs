@@ -0,0 +1,57 @@
+//! Counters and recent notable events of this node, served on `/metrics`.
+
+use crate::blockchain::ValidationError;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+// Events kept for `/metrics`, older ones are dropped
+const MAX_RECENT_EVENTS: usize = 100;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum MetricsEvent {
+    /// A peer's chain was valid but adopting it would revert final blocks
+    ReorgRejected {
+        peer: String,
+        reason: ValidationError,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordedEvent {
+    pub timestamp: i64,
+    #[serde(flatten)]
+    pub event: MetricsEvent,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MetricsSnapshot {
+    pub rejected_reorgs: u64,
+    pub recent_events: VecDeque<RecordedEvent>,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    snapshot: Mutex<MetricsSnapshot>,
+}
+
+impl Metrics {
+    pub fn record(&self, event: MetricsEvent) {
+        let mut snapshot = self.snapshot.lock().unwrap();
+        match &event {
+            MetricsEvent::ReorgRejected { .. } => snapshot.rejected_reorgs += 1,
+        }
+        snapshot.recent_events.push_back(RecordedEvent {
+            timestamp: chrono::Utc::now().timestamp(),
+            event,
+        });
+        if snapshot.recent_events.len() > MAX_RECENT_EVENTS {
+            snapshot.recent_events.pop_front();
+        }
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        self.snapshot.lock().unwrap().clone()
+    }
+}
@@ -1,4 +1,11 @@
 pub mod client;
+pub mod metrics;
 pub mod server;
 
 const POLL_INTERVAL_S: u64 = 10;
+
+// Content type of canonically encoded blocks and headers
+const ENCODED_CONTENT_TYPE: &str = "application/octet-stream";
+
+// Response header listing the block versions a node validates
+const BLOCK_VERSIONS_HEADER: &str = "x-block-versions";
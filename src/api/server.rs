@@ -1,14 +1,19 @@
 use crate::api::client;
+use crate::api::metrics::{Metrics, MetricsEvent};
+use crate::blockchain::block::SUPPORTED_BLOCK_VERSIONS;
 use crate::blockchain::{
-    Block, Chain, ChainInfo, Consensus, Mempool, MessageQueue, MessageTransaction, MiningCommand,
-    MiningCoordinator, MiningInterface,
+    Block, Chain, ChainInfo, Consensus, ConsensusMessage, Mempool, MessageQueue,
+    MessageTransaction, MiningCommand, MiningCoordinator, MiningInterface, ValidationError,
 };
 use crate::frontend::routes::{
     handle_message_from_submit, register_node_form, render_blocks_list, render_dashboard,
     render_nodes_list,
 };
+use crate::utils::encoding::{Decode, Encode};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
 use actix_web::rt::spawn;
 use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use ed25519_dalek::SigningKey;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
@@ -16,20 +21,31 @@ use std::sync::Arc;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::Mutex;
 
-type MiningJoinHandle = tokio::task::JoinHandle<()>;
+// Room for a block header and its proof on top of the body in a posted block
+const BLOCK_HEADER_ALLOWANCE: usize = 64 * 1024;
 
 pub struct AppState<C: Consensus> {
     pub poll_interval_s: u64,
+    #[allow(dead_code)]
     pub chain_file: String,
     pub mining_tx: Sender<MiningCommand>,
-    pub mining_handle: Option<MiningJoinHandle>,
-    pub chain_info: Arc<Mutex<ChainInfo>>,
+    // Blocks to append to the chain, mined here or finalized by vote
+    pub block_tx: Sender<(Block<C::Proof>, Vec<String>)>,
+    pub chain_info: Arc<Mutex<ChainInfo<C::Proof>>>,
+    /// Pending messages, shared with the message queue and the miner
+    pub mempool: Arc<Mutex<Mempool>>,
+    pub metrics: Arc<Metrics>,
+    /// Signs the bare messages submitted to this node
+    pub submission_key: SigningKey,
     _consensus_type: std::marker::PhantomData<C>,
 }
 
-#[derive(Deserialize)]
-pub struct BlockRequest {
-    data: String,
+// Body of a rejected block response, the error fields plus a readable message
+#[derive(Serialize)]
+struct RejectionResponse<'a> {
+    #[serde(flatten)]
+    error: &'a ValidationError,
+    message: String,
 }
 
 #[derive(Deserialize)]
@@ -42,27 +58,149 @@ pub struct RegisteredNodes {
     nodes: HashSet<String>,
 }
 
+/// Body of `/submit`: a transaction signed by the client, given the id derived
+/// from its signed content, or a bare message this node signs with its
+/// submission key.
+// Strict, so a half signed transaction is refused rather than taken for a
+// bare message
 #[derive(Deserialize)]
-pub struct MessageRequest {
-    message: String,
+#[serde(untagged, deny_unknown_fields)]
+pub enum MessageRequest {
+    Signed {
+        sender: String,
+        message: String,
+        timestamp: i64,
+        signature: String,
+    },
+    Unsigned {
+        message: String,
+    },
+}
+
+impl MessageRequest {
+    pub fn into_transaction(self, submission_key: &SigningKey) -> MessageTransaction {
+        match self {
+            MessageRequest::Signed {
+                sender,
+                message,
+                timestamp,
+                signature,
+            } => {
+                let mut transaction = MessageTransaction {
+                    id: String::new(),
+                    sender,
+                    message,
+                    timestamp,
+                    submitted_at: chrono::Utc::now().timestamp(),
+                    signature,
+                };
+                transaction.id = transaction.derived_id();
+                transaction
+            }
+            MessageRequest::Unsigned { message } => {
+                MessageTransaction::signed(submission_key, message)
+            }
+        }
+    }
 }
 
 pub async fn alive() -> impl Responder {
     HttpResponse::Ok().body("Node alive")
 }
 
-// Get /chain: Returns current chain
-pub async fn get_chain<C: Consensus>(data: web::Data<Arc<Mutex<Chain<C>>>>) -> impl Responder {
+// Responds with the canonical encoding of `value` to clients accepting it,
+// with JSON otherwise
+fn negotiated<T: Encode + Serialize>(req: &HttpRequest, value: &T) -> HttpResponse {
+    let accepts_encoded = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains(super::ENCODED_CONTENT_TYPE));
+    if accepts_encoded {
+        HttpResponse::Ok()
+            .content_type(super::ENCODED_CONTENT_TYPE)
+            .body(value.encode())
+    } else {
+        HttpResponse::Ok().json(value)
+    }
+}
+
+// Tells clients which block versions this node validates, so they can tell
+// an upgraded network apart from a faulty peer
+fn with_block_versions(mut response: HttpResponse) -> HttpResponse {
+    let versions = SUPPORTED_BLOCK_VERSIONS
+        .iter()
+        .map(u32::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+    response.headers_mut().insert(
+        HeaderName::from_static(super::BLOCK_VERSIONS_HEADER),
+        HeaderValue::from_str(&versions).expect("Version lists are valid header values"),
+    );
+    response
+}
+
+// Reads a canonically encoded or a JSON request body, by its content type
+fn decode_body<T: Decode + DeserializeOwned>(req: &HttpRequest, body: &[u8]) -> Result<T, String> {
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or_default();
+    if content_type.starts_with(super::ENCODED_CONTENT_TYPE) {
+        T::decode(body).map_err(|e| e.to_string())
+    } else {
+        serde_json::from_slice(body).map_err(|e| e.to_string())
+    }
+}
+
+// Get /chain: Returns current chain, advertising the supported block versions
+pub async fn get_chain<C: Consensus>(
+    data: web::Data<Arc<Mutex<Chain<C>>>>,
+    req: HttpRequest,
+) -> impl Responder {
     let chain = data.lock().await;
-    HttpResponse::Ok().json(chain.chain.clone())
+    with_block_versions(negotiated(&req, &chain.chain))
 }
 
-// Post /block : Receives a new block and validates it
+// Get /headers: Returns the headers of the current chain, without transactions
+pub async fn get_headers<C: Consensus>(
+    data: web::Data<Arc<Mutex<Chain<C>>>>,
+    req: HttpRequest,
+) -> impl Responder {
+    let chain = data.lock().await;
+    negotiated(&req, &chain.headers())
+}
+
+// Get /transactions/{id}/proof : Merkle proof that a transaction is in the chain
+pub async fn get_transaction_proof<C: Consensus>(
+    data: web::Data<Arc<Mutex<Chain<C>>>>,
+    transaction_id: web::Path<String>,
+) -> impl Responder {
+    let chain = data.lock().await;
+    let hash_algorithm = chain.consensus.rules().hash;
+    let proof = chain
+        .chain
+        .iter()
+        .rev()
+        .find_map(|block| block.inclusion_proof(hash_algorithm, &transaction_id));
+
+    match proof {
+        Some(proof) => HttpResponse::Ok().json(proof),
+        None => HttpResponse::NotFound().body(format!(
+            "Transaction {} is not in the chain",
+            transaction_id
+        )),
+    }
+}
+
+// Post /block : Receives a new block and validates it, advertising the
+// supported block versions
 pub async fn post_block<C: Consensus>(
     data: web::Data<Arc<Mutex<Chain<C>>>>,
     app_state: web::Data<AppState<C>>,
     req: HttpRequest,
-    block: web::Json<Block<C::Proof>>,
+    body: web::Bytes,
 ) -> impl Responder
 where
     C::Proof: Serialize + Clone + Send + 'static,
@@ -73,33 +211,53 @@ where
         .peer_addr()
         .unwrap_or("unknown")
         .to_string();
+    let block = match decode_body::<Block<C::Proof>>(&req, &body) {
+        Ok(block) => block,
+        Err(e) => {
+            return with_block_versions(
+                HttpResponse::BadRequest().body(format!("Invalid block: {}", e)),
+            )
+        }
+    };
 
-    let (is_valid, nodes, block_inner) = {
+    let (verdict, nodes, block_inner) = {
         let mut chain = data.lock().await;
-        let is_valid = chain
-            .consensus
-            .validate_block(chain.chain.last().unwrap(), &block);
+        let recent_headers = chain.recent_headers();
+        let verdict = if chain.consensus.decides_by_vote(&recent_headers) {
+            Err(ValidationError::DecidedByVote {
+                index: block.header.index,
+            })
+        } else {
+            chain
+                .consensus
+                .validate_block(&recent_headers, chain.transaction_ids(), &block)
+                .and_then(|_| chain.finality.check_block(&block.header))
+        };
 
-        if is_valid {
-            if let Ok(transactions) = serde_json::from_str::<Vec<MessageTransaction>>(&block.data) {
-                let transaction_ids: Vec<String> =
-                    transactions.iter().map(|tx| tx.id.clone()).collect();
-                chain.mempool.remove_messages(&transaction_ids);
-            }
+        if verdict.is_ok() {
+            let message_ids = block.body.transaction_ids();
+            chain.mempool.remove_messages(&message_ids);
+            app_state.mempool.lock().await.remove_messages(&message_ids);
 
-            let block_inner = block.into_inner();
-            chain.chain.push(block_inner.clone());
+            chain.push_block(block.clone());
 
-            let mut info = app_state.chain_info.lock().await;
-            info.length = chain.chain.len() as u64;
-            info.last_hash = block_inner.hash.clone();
+            app_state.chain_info.lock().await.update(&chain.chain);
 
-            (true, chain.nodes.clone(), block_inner)
+            (verdict, chain.nodes.clone(), block)
         } else {
-            (false, HashSet::new(), block.into_inner())
+            (verdict, HashSet::new(), block)
         }
     };
-    if is_valid {
+    if let Err(error) = verdict {
+        eprintln!(
+            "Rejected block #{} from {}: {}",
+            block_inner.header.index, sender, error
+        );
+        with_block_versions(HttpResponse::BadRequest().json(RejectionResponse {
+            error: &error,
+            message: error.to_string(),
+        }))
+    } else {
         let block_clone = block_inner.clone();
         tokio::spawn(async move {
             if let Err(e) =
@@ -108,9 +266,40 @@ where
                 eprintln!("Error propagating block:{}", e)
             }
         });
-        HttpResponse::Ok().body("block added")
-    } else {
-        HttpResponse::BadRequest().body("Invalid block")
+        with_block_versions(HttpResponse::Ok().body("block added"))
+    }
+}
+
+// Post /consensus : Receives a proposal or vote from another validator, in
+// the canonical encoding or as JSON
+pub async fn post_consensus_message<C: Consensus>(
+    data: web::Data<Arc<Mutex<Chain<C>>>>,
+    app_state: web::Data<AppState<C>>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> impl Responder {
+    let message = match decode_body::<ConsensusMessage>(&req, &body) {
+        Ok(message) => message,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid message: {}", e)),
+    };
+    let verdict = {
+        let chain = data.lock().await;
+        chain
+            .consensus
+            .handle_message(&chain.recent_headers(), chain.transaction_ids(), message)
+    };
+    match verdict {
+        Ok(Some(block)) => {
+            println!("Block #{} finalized", block.header.index);
+            let message_ids = block.body.transaction_ids();
+            let _ = app_state.block_tx.send((block, message_ids)).await;
+            HttpResponse::Ok().body("block finalized")
+        }
+        Ok(None) => HttpResponse::Ok().body("message accepted"),
+        Err(error) => HttpResponse::BadRequest().json(RejectionResponse {
+            error: &error,
+            message: error.to_string(),
+        }),
     }
 }
 
@@ -146,7 +335,9 @@ pub async fn get_nodes<C: Consensus>(data: web::Data<Arc<Mutex<Chain<C>>>>) -> i
 
 async fn synchronize_chain<C: Consensus>(
     chain_data: &Arc<Mutex<Chain<C>>>,
-    chain_info: &Arc<Mutex<ChainInfo>>,
+    chain_info: &Arc<Mutex<ChainInfo<C::Proof>>>,
+    mempool: &Arc<Mutex<Mempool>>,
+    metrics: &Metrics,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let nodes = {
         let chain = chain_data.lock().await;
@@ -155,40 +346,104 @@ async fn synchronize_chain<C: Consensus>(
     if nodes.is_empty() {
         return Ok(());
     }
-    let mut max_len = 0;
-    let mut best_chain: Option<Vec<Block<C::Proof>>> = None;
+    let (consensus, genesis_hash, local_length, local_weight, tip_commit) = {
+        let chain = chain_data.lock().await;
+        let local_weight = chain.consensus.chain_weight(&chain.headers());
+        let tip_commit = chain
+            .chain
+            .last()
+            .and_then(|tip| chain.consensus.tip_commit(tip));
+        (
+            chain.consensus.clone(),
+            chain.genesis_hash.clone(),
+            chain.chain.len(),
+            local_weight,
+            tip_commit,
+        )
+    };
+
+    // Peers' chains are compared on their headers, only the heaviest one is
+    // then downloaded in full
+    let mut max_weight = local_weight;
+    let mut best_peer: Option<String> = None;
     for node in nodes {
-        match client::sync_chain::<C>(&node).await {
-            Ok(response) => {
-                let temp_chain = Chain {
-                    chain: response,
-                    nodes: Default::default(),
-                    consensus: chain_data.lock().await.consensus.clone(),
-                    mempool: Mempool::new(10, 100),
-                };
-                if temp_chain.consensus.validate_chain(&temp_chain)
-                    && temp_chain.chain.len() > max_len
-                {
-                    max_len = temp_chain.chain.len();
-                    best_chain = Some(temp_chain.chain);
-                }
+        let mut headers = match client::sync_headers::<C>(&node).await {
+            Ok(headers) => headers,
+            Err(e) => {
+                eprintln!("Error fetching headers from {}: {}", node, e);
+                continue;
             }
-            Err(_) => continue,
+        };
+        // A peer that missed the commit of our tip cannot vote on the next
+        // block, nor fetch the certificate along with the chain
+        if let Some(commit) = tip_commit.as_ref().filter(|_| headers.len() < local_length) {
+            client::broadcast_consensus_message(&HashSet::from([node.clone()]), commit).await;
+        }
+        // A tip decided by vote is only adopted along with its certificate
+        if !consensus.tip_certified(&headers) {
+            headers.pop();
         }
+        if let Err(error) = consensus.validate_headers(&genesis_hash, &headers) {
+            eprintln!("Rejected chain from {}: {}", node, error);
+            continue;
+        }
+        // Only a chain heavier than ours would be adopted, a peer behind us
+        // is no reorg to reject
+        let weight = consensus.chain_weight(&headers);
+        if weight <= max_weight {
+            continue;
+        }
+        let reorg = chain_data.lock().await.check_reorg(&headers);
+        if let Err(reason) = reorg {
+            eprintln!("Rejected reorg to the chain of {}: {}", node, reason);
+            metrics.record(MetricsEvent::ReorgRejected { peer: node, reason });
+            continue;
+        }
+        max_weight = weight;
+        best_peer = Some(node);
     }
 
-    if let Some(new_chain) = best_chain {
-        let mut chain = chain_data.lock().await;
-        if new_chain.len() > chain.chain.len() {
-            chain.chain = new_chain;
-
-            let last_block = chain.chain.last().unwrap();
-            let mut info = chain_info.lock().await;
-            info.length = chain.chain.len() as u64;
-            info.last_hash = last_block.hash.clone();
+    let Some(node) = best_peer else {
+        return Ok(());
+    };
 
-            println!("Chain updated. New length {}", max_len);
+    // The peer may have moved on since sending its headers, so its full chain
+    // is validated afresh
+    let mut blocks = match client::sync_chain::<C>(&node).await {
+        Ok(blocks) => blocks,
+        Err(e) => {
+            eprintln!("Error fetching chain from {}: {}", node, e);
+            return Ok(());
         }
+    };
+    if blocks
+        .last()
+        .is_some_and(|tip| !consensus.tip_certified(std::slice::from_ref(&tip.header)))
+    {
+        blocks.pop();
+    }
+    let temp_chain = Chain::from_blocks(blocks, genesis_hash, consensus);
+    if let Err(error) = temp_chain.consensus.validate_chain(&temp_chain) {
+        eprintln!("Rejected chain from {}: {}", node, error);
+        return Ok(());
+    }
+    let new_headers = temp_chain.headers();
+    let new_weight = temp_chain.consensus.chain_weight(&new_headers);
+
+    let mut chain = chain_data.lock().await;
+    // The local chain may have moved on while peers were queried
+    if new_weight > chain.consensus.chain_weight(&chain.headers())
+        && chain.check_reorg(&new_headers).is_ok()
+    {
+        chain.replace_blocks(temp_chain.chain);
+        mempool.lock().await.prune(chain.transaction_ids());
+        chain_info.lock().await.update(&chain.chain);
+
+        println!(
+            "Chain updated. New length {}, weight {}",
+            chain.chain.len(),
+            new_weight
+        );
     }
     Ok(())
 }
@@ -198,12 +453,13 @@ pub async fn api_submit_message<C: Consensus>(
     app_state: web::Data<AppState<C>>,
     req: web::Json<MessageRequest>,
 ) -> impl Responder {
-    match message_queue.submit_message(req.message.clone()).await {
+    let transaction = req.into_inner().into_transaction(&app_state.submission_key);
+    match message_queue.submit_message(transaction).await {
         Ok(_) => {
             let _ = app_state.mining_tx.send(MiningCommand::StartMining).await;
             HttpResponse::Ok().body("Message queued successfully")
         }
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => HttpResponse::BadRequest().body(e),
     }
 }
 
@@ -215,25 +471,27 @@ where
     C::Proof: Serialize,
     Block<C::Proof>: Serialize,
 {
-    let (block_option, nodes, chain_len) = {
+    let (block_option, nodes) = {
         let mut chain = data.lock().await;
         let timestamp = chrono::Utc::now().timestamp();
         let block = chain.new_block(timestamp).await;
-        let nodes = if block.is_some() {
+        let nodes = if let Some(block) = &block {
+            app_state
+                .mempool
+                .lock()
+                .await
+                .remove_messages(&block.body.transaction_ids());
+            app_state.chain_info.lock().await.update(&chain.chain);
             chain.nodes.clone()
         } else {
             HashSet::new()
         };
-        let chain_len = chain.chain.len() as u64;
-        (block, nodes, chain_len)
+        (block, nodes)
     };
 
     match block_option {
         Some(block) => {
             let block_clone = block.clone();
-            let mut info = app_state.chain_info.lock().await;
-            info.length = chain_len;
-            info.last_hash = block.hash.clone();
             tokio::spawn(async move {
                 if let Err(e) =
                     crate::api::client::broadcast_block::<C>(&nodes, &block_clone, None).await
@@ -243,7 +501,9 @@ where
             });
             HttpResponse::Ok().json(block)
         }
-        None => HttpResponse::BadRequest().body("No pending transactions in mempool"),
+        None => HttpResponse::BadRequest().body(
+            "No block generated: mempool is empty or this node may not produce the next block",
+        ),
     }
 }
 
@@ -274,9 +534,34 @@ pub async fn stop_mining<C: Consensus>(app_state: web::Data<AppState<C>>) -> imp
     HttpResponse::Ok().body("Stopped Mining")
 }
 
+pub async fn get_metrics<C: Consensus>(app_state: web::Data<AppState<C>>) -> impl Responder {
+    HttpResponse::Ok().json(app_state.metrics.snapshot())
+}
+
+pub async fn get_mining_status<C: Consensus>(
+    data: web::Data<Arc<Mutex<Chain<C>>>>,
+) -> impl Responder {
+    let chain = data.lock().await;
+
+    #[derive(Serialize)]
+    struct MiningStatus {
+        hashrate: Option<u64>,
+    }
+
+    HttpResponse::Ok().json(MiningStatus {
+        hashrate: chain.consensus.hashrate(),
+    })
+}
+
 fn configure_api_routes<C: Consensus>(cfg: &mut web::ServiceConfig) {
     cfg.route("/chain", web::get().to(get_chain::<C>))
+        .route("/headers", web::get().to(get_headers::<C>))
         .route("/block", web::post().to(post_block::<C>))
+        .route(
+            "/transactions/{id}/proof",
+            web::get().to(get_transaction_proof::<C>),
+        )
+        .route("/consensus", web::post().to(post_consensus_message::<C>))
         .route("/generate", web::post().to(generate_block::<C>))
         .route("/submit", web::post().to(api_submit_message::<C>))
         .route("/pending", web::get().to(get_pending_transactions::<C>))
@@ -284,6 +569,8 @@ fn configure_api_routes<C: Consensus>(cfg: &mut web::ServiceConfig) {
         .route("/nodes/register", web::post().to(register_node::<C>))
         .route("/mining/start", web::post().to(start_mining::<C>))
         .route("/mining/end", web::post().to(stop_mining::<C>))
+        .route("/mining/status", web::get().to(get_mining_status::<C>))
+        .route("/metrics", web::get().to(get_metrics::<C>))
         .route("/alive", web::get().to(alive));
 }
 
@@ -301,17 +588,32 @@ fn configure_frontend_routes<C: Consensus>(cfg: &mut web::ServiceConfig) {
 
 // Start server with given chain and address
 pub async fn run_server<C: Consensus>(
-    chain: Chain<C>,
+    mut chain: Chain<C>,
     address: &str,
     chain_file: String,
+    submission_key: SigningKey,
 ) -> std::io::Result<()>
 where
     C::Proof: Serialize + DeserializeOwned + Send + Sync + 'static,
 {
+    let (outbox_tx, mut outbox_rx) = tokio::sync::mpsc::unbounded_channel::<ConsensusMessage>();
+    chain.consensus.set_outbox(outbox_tx);
+    // Posted blocks may be JSON, larger than the encoding the limit counts
+    let block_payload_limit = chain.consensus.rules().max_block_bytes * 4 + BLOCK_HEADER_ALLOWANCE;
+
     let chain_data = Arc::new(tokio::sync::Mutex::new(chain));
     let web_chain_data = web::Data::new(chain_data.clone());
     println!("Starting rustchain node on port {}", address);
 
+    // Sends proposals and votes in the order the engine produced them
+    let outbox_chain_data = chain_data.clone();
+    tokio::spawn(async move {
+        while let Some(message) = outbox_rx.recv().await {
+            let nodes = outbox_chain_data.lock().await.nodes.clone();
+            client::broadcast_consensus_message(&nodes, &message).await;
+        }
+    });
+
     let (block_tx, mut block_rx) = tokio::sync::mpsc::channel::<(Block<C::Proof>, Vec<String>)>(32);
 
     let mempool = {
@@ -319,69 +621,88 @@ where
         Arc::new(Mutex::new(chain.mempool.clone()))
     };
 
-    let message_queue = MessageQueue::new(mempool.clone());
+    let message_queue = MessageQueue::new(mempool.clone(), chain_data.clone());
     let message_queue_data = web::Data::new(message_queue.clone());
 
     let chain_info = {
         let chain = chain_data.lock().await;
-        let last_block = chain.chain.last().unwrap();
-        Arc::new(Mutex::new(ChainInfo {
-            length: chain.chain.len() as u64,
-            last_hash: last_block.hash.clone(),
-        }))
+        Arc::new(Mutex::new(ChainInfo::new(
+            &chain.chain,
+            chain.consensus.history_len(),
+        )))
     };
 
     let mining_interface = MiningInterface {
         mempool_accessor: mempool.clone(),
+        chain: chain_data.clone(),
         chain_info: chain_info.clone(),
         consensus: chain_data.lock().await.consensus.clone(),
-        block_channel: block_tx,
+        block_channel: block_tx.clone(),
     };
 
     let (mut mining_coordinator, mining_tx) = MiningCoordinator::new(mining_interface, 100);
 
     let block_receiver_chain_data = chain_data.clone();
     let block_receiver_chain_info = chain_info.clone();
+    let block_receiver_mempool = mempool.clone();
+    let metrics = Arc::new(Metrics::default());
+    let block_receiver_metrics = metrics.clone();
 
     tokio::spawn(async move {
         while let Some((block, message_ids)) = block_rx.recv().await {
             let mut chain = block_receiver_chain_data.lock().await;
+            if chain.chain.len() as u64 != block.header.index {
+                println!(
+                    "Chain changed during mining, discarding block #{}",
+                    block.header.index
+                );
+                drop(chain);
+                if let Err(e) = synchronize_chain(
+                    &block_receiver_chain_data,
+                    &block_receiver_chain_info,
+                    &block_receiver_mempool,
+                    &block_receiver_metrics,
+                )
+                .await
+                {
+                    eprintln!("Error synching chain after discard: {}", e);
+                }
+                continue;
+            }
+            let recent_headers = chain.recent_headers();
+            let verdict =
+                chain
+                    .consensus
+                    .validate_block(&recent_headers, chain.transaction_ids(), &block);
+            if let Err(error) = verdict {
+                eprintln!("Rejected block #{}: {}", block.header.index, error);
+                continue;
+            }
+            // Blocks decided by vote reach peers as commits instead
+            let broadcast = !chain.consensus.decides_by_vote(&recent_headers);
+            chain.push_block(block.clone());
+
+            // Only now that they are in the chain may the messages leave the mempool
             println!(
-                "Received Block #{}, removing {} messages",
-                block.index,
+                "Block #{} added to chain, removing {} messages",
+                block.header.index,
                 message_ids.len()
             );
-            //TODO: CRITICAL: Only remove if the msgs are in the chain.
             chain.mempool.remove_messages(&message_ids);
-            if chain.chain.len() as u64 == block.index {
-                chain.chain.push(block.clone());
+            block_receiver_mempool
+                .lock()
+                .await
+                .remove_messages(&message_ids);
 
-                let block_hash = block.hash.clone();
-                let block_index = block.index;
-                let nodes = chain.nodes.clone();
-                {
-                    let mut info = block_receiver_chain_info.lock().await;
-                    info.length = chain.chain.len() as u64;
-                    info.last_hash = block_hash;
-                }
+            let nodes = chain.nodes.clone();
+            block_receiver_chain_info.lock().await.update(&chain.chain);
 
-                let block_clone = block.clone();
+            if broadcast {
                 tokio::spawn(async move {
-                    if let Err(e) = client::broadcast_block::<C>(&nodes, &block_clone, None).await {
+                    if let Err(e) = client::broadcast_block::<C>(&nodes, &block, None).await {
                         eprintln!("Error broadcasting mined block: {}", e);
                     }
                 });
-                println!("Block #{} added to chain", block_index);
-            } else {
-                println!(
-                    "Chain changed during mining, discarding block #{}",
-                    block.index
-                );
-                if let Err(e) =
-                    synchronize_chain(&block_receiver_chain_data, &block_receiver_chain_info).await
-                {
-                    eprintln!("Error synching chain after discard: {}", e);
-                }
             }
         }
     });
@@ -396,14 +717,15 @@ where
         mining_coordinator.run().await;
     });
 
-    let mining_runtime = Arc::new(mining_runtime);
-
     let app_state = web::Data::new(AppState::<C> {
         poll_interval_s: super::POLL_INTERVAL_S,
         chain_file: chain_file.clone(),
         mining_tx: mining_tx.clone(),
-        mining_handle: Some(mining_handle),
+        block_tx,
         chain_info: chain_info.clone(),
+        mempool: mempool.clone(),
+        metrics: metrics.clone(),
+        submission_key,
         _consensus_type: std::marker::PhantomData,
     });
 
@@ -411,12 +733,21 @@ where
 
     let sync_chain_data = chain_data.clone();
     let sync_chain_info = chain_info.clone();
+    let sync_mempool = mempool.clone();
+    let sync_metrics = metrics.clone();
 
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(10));
         loop {
             interval.tick().await;
-            if let Err(e) = synchronize_chain(&sync_chain_data, &sync_chain_info).await {
+            if let Err(e) = synchronize_chain(
+                &sync_chain_data,
+                &sync_chain_info,
+                &sync_mempool,
+                &sync_metrics,
+            )
+            .await
+            {
                 eprintln!("Error synchronizing chain: {}", e);
             }
         }
@@ -441,16 +772,27 @@ where
         }
     });
 
-    HttpServer::new(move || {
+    let server_result = HttpServer::new(move || {
         App::new()
             .app_data(web_chain_data.clone())
             .app_data(app_state.clone())
             .app_data(message_queue_data.clone())
+            .app_data(web::PayloadConfig::new(block_payload_limit))
             .configure(configure_api_routes::<C>)
             .configure(configure_frontend_routes::<C>)
-            .app_data(web::Data::new(mining_runtime.clone()))
     })
     .bind(address)?
     .run()
-    .await
+    .await;
+
+    // Abort any in-flight proof search, then shut the mining runtime down off
+    // the async context, which tokio does not allow to block
+    let _ = mining_tx.send(MiningCommand::Shutdown).await;
+    let _ = tokio::time::timeout(std::time::Duration::from_secs(5), mining_handle).await;
+    let _ = tokio::task::spawn_blocking(move || {
+        mining_runtime.shutdown_timeout(std::time::Duration::from_secs(5))
+    })
+    .await;
+
+    server_result
 }
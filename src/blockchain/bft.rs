@@ -0,0 +1,1195 @@
+//! Byzantine fault tolerant finality for permissioned networks.
+//!
+//! A fixed set of validators agrees on every block in Tendermint style rounds.
+//! The proposer of a round sends its block to every node, validators prevote
+//! for it and, once two thirds of them did, precommit to it. Two thirds of
+//! precommits make the block final: every node appends it and it can no
+//! longer be reverted by a fork. Nodes that decided a block send it on with
+//! its precommits, so nodes that missed votes can append it too, but no node
+//! appends a block it has not seen two thirds of precommits for.
+//!
+//! The precommits are only complete after the block hash is fixed, so the
+//! commit certificate finalizing a block travels in the proof of the next one.
+//! The tip of a chain carries no certificate yet, so nodes only adopt a peer's
+//! tip once they saw its commit, and send the commit of their own tip to
+//! peers found behind them.
+//! A validator that precommitted a block stays locked on it for the height
+//! and re-proposes it in its own rounds, until a later round gathers two
+//! thirds of prevotes for another block.
+
+use super::block::BLOCK_VERSION;
+use super::consensus::{expect_proof_tag, next_position, seal_hash};
+use super::{Block, BlockBody, BlockHeader, BlockRules, Consensus, ValidationError};
+use crate::utils::encoding::{Decode, DecodeError, Decoder, Encode};
+use crate::utils::keys;
+use core::fmt;
+use ed25519_dalek::SigningKey;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_util::sync::CancellationToken;
+
+/// Precommits of at least two thirds of the validators for one block.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommitCertificate {
+    pub round: u32,
+    /// Precommit signature of each committing validator, by public key
+    pub precommits: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BftProof {
+    pub proposer: String,
+    pub signature: String,
+    /// Certificate finalizing the parent block, absent after genesis
+    pub parent_commit: Option<CommitCertificate>,
+}
+
+impl BftProof {
+    fn unsigned() -> Self {
+        BftProof {
+            proposer: String::new(),
+            signature: String::new(),
+            parent_commit: None,
+        }
+    }
+}
+
+// Leading byte of a BFT proof in the canonical encoding
+pub(super) const BFT_PROOF_TAG: u8 = 2;
+
+impl Encode for CommitCertificate {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.round.encode_to(out);
+        self.precommits.encode_to(out);
+    }
+}
+
+impl Decode for CommitCertificate {
+    fn decode_from(input: &mut Decoder<'_>) -> Result<Self, DecodeError> {
+        Ok(CommitCertificate {
+            round: Decode::decode_from(input)?,
+            precommits: Decode::decode_from(input)?,
+        })
+    }
+}
+
+impl Encode for BftProof {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        BFT_PROOF_TAG.encode_to(out);
+        self.proposer.encode_to(out);
+        self.signature.encode_to(out);
+        self.parent_commit.encode_to(out);
+    }
+}
+
+impl Decode for BftProof {
+    fn decode_from(input: &mut Decoder<'_>) -> Result<Self, DecodeError> {
+        expect_proof_tag(input, BFT_PROOF_TAG)?;
+        Ok(BftProof {
+            proposer: Decode::decode_from(input)?,
+            signature: Decode::decode_from(input)?,
+            parent_commit: Decode::decode_from(input)?,
+        })
+    }
+}
+
+impl fmt::Display for BftProof {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.proposer.is_empty() {
+            write!(f, "unsigned")
+        } else {
+            write!(f, "proposed by {}", self.proposer)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VoteKind {
+    Prevote,
+    Precommit,
+}
+
+impl fmt::Display for VoteKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VoteKind::Prevote => write!(f, "prevote"),
+            VoteKind::Precommit => write!(f, "precommit"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Vote {
+    pub kind: VoteKind,
+    pub height: u64,
+    pub round: u32,
+    pub block_hash: String,
+    pub validator: String,
+    pub signature: String,
+}
+
+// Bytes a validator signs to vote for `block_hash`
+fn vote_message(kind: VoteKind, height: u64, round: u32, block_hash: &str) -> Vec<u8> {
+    format!("{}|{}|{}|{}", kind, height, round, block_hash).into_bytes()
+}
+
+/// A block put up for a vote by the proposer of `round`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Proposal {
+    pub round: u32,
+    pub block: Block<BftProof>,
+    pub proposer: String,
+    pub signature: String,
+}
+
+fn proposal_message(round: u32, block: &Block<BftProof>) -> Vec<u8> {
+    format!(
+        "proposal|{}|{}|{}",
+        block.header.index, round, block.header.hash
+    )
+    .into_bytes()
+}
+
+/// A decided block and the certificate finalizing it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Commit {
+    pub block: Block<BftProof>,
+    pub certificate: CommitCertificate,
+}
+
+/// Message exchanged between nodes by engines that vote on blocks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ConsensusMessage {
+    Proposal(Box<Proposal>),
+    Vote(Vote),
+    Commit(Box<Commit>),
+}
+
+// Leading byte of each kind of consensus message and vote in the canonical
+// encoding
+const PROPOSAL_TAG: u8 = 0;
+const VOTE_TAG: u8 = 1;
+const COMMIT_TAG: u8 = 2;
+const PREVOTE_TAG: u8 = 0;
+const PRECOMMIT_TAG: u8 = 1;
+
+impl Encode for VoteKind {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        match self {
+            VoteKind::Prevote => PREVOTE_TAG.encode_to(out),
+            VoteKind::Precommit => PRECOMMIT_TAG.encode_to(out),
+        }
+    }
+}
+
+impl Decode for VoteKind {
+    fn decode_from(input: &mut Decoder<'_>) -> Result<Self, DecodeError> {
+        match u8::decode_from(input)? {
+            PREVOTE_TAG => Ok(VoteKind::Prevote),
+            PRECOMMIT_TAG => Ok(VoteKind::Precommit),
+            tag => Err(DecodeError::UnknownTag { kind: "vote", tag }),
+        }
+    }
+}
+
+impl Encode for Vote {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.kind.encode_to(out);
+        self.height.encode_to(out);
+        self.round.encode_to(out);
+        self.block_hash.encode_to(out);
+        self.validator.encode_to(out);
+        self.signature.encode_to(out);
+    }
+}
+
+impl Decode for Vote {
+    fn decode_from(input: &mut Decoder<'_>) -> Result<Self, DecodeError> {
+        Ok(Vote {
+            kind: Decode::decode_from(input)?,
+            height: Decode::decode_from(input)?,
+            round: Decode::decode_from(input)?,
+            block_hash: Decode::decode_from(input)?,
+            validator: Decode::decode_from(input)?,
+            signature: Decode::decode_from(input)?,
+        })
+    }
+}
+
+impl Encode for Proposal {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.round.encode_to(out);
+        self.block.encode_to(out);
+        self.proposer.encode_to(out);
+        self.signature.encode_to(out);
+    }
+}
+
+impl Decode for Proposal {
+    fn decode_from(input: &mut Decoder<'_>) -> Result<Self, DecodeError> {
+        Ok(Proposal {
+            round: Decode::decode_from(input)?,
+            block: Decode::decode_from(input)?,
+            proposer: Decode::decode_from(input)?,
+            signature: Decode::decode_from(input)?,
+        })
+    }
+}
+
+impl Encode for Commit {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.block.encode_to(out);
+        self.certificate.encode_to(out);
+    }
+}
+
+impl Decode for Commit {
+    fn decode_from(input: &mut Decoder<'_>) -> Result<Self, DecodeError> {
+        Ok(Commit {
+            block: Decode::decode_from(input)?,
+            certificate: Decode::decode_from(input)?,
+        })
+    }
+}
+
+impl Encode for ConsensusMessage {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        match self {
+            ConsensusMessage::Proposal(proposal) => {
+                PROPOSAL_TAG.encode_to(out);
+                proposal.encode_to(out);
+            }
+            ConsensusMessage::Vote(vote) => {
+                VOTE_TAG.encode_to(out);
+                vote.encode_to(out);
+            }
+            ConsensusMessage::Commit(commit) => {
+                COMMIT_TAG.encode_to(out);
+                commit.encode_to(out);
+            }
+        }
+    }
+}
+
+impl Decode for ConsensusMessage {
+    fn decode_from(input: &mut Decoder<'_>) -> Result<Self, DecodeError> {
+        match u8::decode_from(input)? {
+            PROPOSAL_TAG => Ok(ConsensusMessage::Proposal(Box::new(Decode::decode_from(
+                input,
+            )?))),
+            VOTE_TAG => Ok(ConsensusMessage::Vote(Decode::decode_from(input)?)),
+            COMMIT_TAG => Ok(ConsensusMessage::Commit(Box::new(Decode::decode_from(
+                input,
+            )?))),
+            tag => Err(DecodeError::UnknownTag {
+                kind: "consensus message",
+                tag,
+            }),
+        }
+    }
+}
+
+// Votes and proposals seen for the height being decided
+#[derive(Debug, Default)]
+struct RoundState {
+    height: u64,
+    parent_hash: String,
+    proposals: BTreeMap<u32, Block<BftProof>>,
+    // Signatures by validator for each kind, round and block hash
+    votes: HashMap<(VoteKind, u32, String), BTreeMap<String, String>>,
+    voted: HashSet<(VoteKind, u32)>,
+    // Round and hash of the block this node precommitted to
+    locked: Option<(u32, String)>,
+    decided: bool,
+    // Hash of the last block decided here and its certificate, which goes
+    // into the proof of the next block
+    last_commit: Option<(String, CommitCertificate)>,
+    // Precommits for the parent arriving after it was appended, by round
+    parent_precommits: BTreeMap<u32, BTreeMap<String, String>>,
+}
+
+impl RoundState {
+    // Starts over when the chain moved past the height being decided. The
+    // parent may have been appended from a peer before its precommits were
+    // all in, those seen so far are kept towards its certificate.
+    fn advance_to(&mut self, parent: &BlockHeader<BftProof>, quorum: usize) {
+        if self.height == parent.index + 1 && self.parent_hash == parent.hash {
+            return;
+        }
+        let mut parent_precommits = BTreeMap::new();
+        if self.height == parent.index {
+            for ((kind, round, hash), signatures) in &self.votes {
+                if *kind == VoteKind::Precommit && *hash == parent.hash {
+                    parent_precommits.insert(*round, signatures.clone());
+                }
+            }
+        }
+        *self = RoundState {
+            height: parent.index + 1,
+            parent_hash: parent.hash.clone(),
+            last_commit: self.last_commit.take(),
+            parent_precommits,
+            ..RoundState::default()
+        };
+        self.certify_parent(quorum);
+    }
+
+    // Completes the parent's certificate from late precommits
+    fn certify_parent(&mut self, quorum: usize) {
+        if matches!(&self.last_commit, Some((hash, _)) if *hash == self.parent_hash) {
+            return;
+        }
+        let complete = self
+            .parent_precommits
+            .iter()
+            .find(|(_, signatures)| signatures.len() >= quorum);
+        if let Some((round, precommits)) = complete {
+            let certificate = CommitCertificate {
+                round: *round,
+                precommits: precommits.clone(),
+            };
+            self.last_commit = Some((self.parent_hash.clone(), certificate));
+        }
+    }
+
+    fn locked_block(&self) -> Option<&Block<BftProof>> {
+        let (_, hash) = self.locked.as_ref()?;
+        self.proposals
+            .values()
+            .find(|block| &block.header.hash == hash)
+    }
+
+    fn proposed(&self, hash: &str) -> Option<&Block<BftProof>> {
+        self.proposals
+            .values()
+            .find(|block| block.header.hash == hash)
+    }
+
+    // Rounds and hashes that gathered `quorum` votes of `kind`, oldest first
+    fn quorums(&self, kind: VoteKind, quorum: usize) -> Vec<(u32, String)> {
+        let mut reached: Vec<(u32, String)> = self
+            .votes
+            .iter()
+            .filter(|((vote_kind, _, _), signatures)| {
+                *vote_kind == kind && signatures.len() >= quorum
+            })
+            .map(|((_, round, hash), _)| (*round, hash.clone()))
+            .collect();
+        reached.sort();
+        reached
+    }
+}
+
+/// Tendermint style agreement among a fixed validator set. Blocks are final
+/// as soon as they are appended.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BftFinality {
+    #[serde(default)]
+    pub(super) rules: BlockRules,
+    validators: Vec<String>,
+    /// Seconds before a round without a decision passes to the next proposer
+    round_timeout_s: i64,
+    #[serde(skip)]
+    signing_key: Option<SigningKey>,
+    #[serde(skip)]
+    rounds: Arc<Mutex<RoundState>>,
+    #[serde(skip)]
+    outbox: Option<UnboundedSender<ConsensusMessage>>,
+}
+
+impl BftFinality {
+    pub fn new(rules: BlockRules, validators: Vec<String>, round_timeout_s: i64) -> Self {
+        BftFinality {
+            rules,
+            validators,
+            round_timeout_s: round_timeout_s.max(1),
+            signing_key: None,
+            rounds: Arc::default(),
+            outbox: None,
+        }
+    }
+
+    pub fn set_signing_key(&mut self, key: SigningKey) {
+        self.signing_key = Some(key);
+    }
+
+    /// Votes needed to prevote, precommit or finalize: more than two thirds.
+    pub fn quorum(&self) -> usize {
+        self.validators.len() * 2 / 3 + 1
+    }
+
+    /// Validator proposing the block at `height` in `round`, rotating every
+    /// height and every round.
+    pub fn proposer_for(&self, height: u64, round: u32) -> Option<&str> {
+        if self.validators.is_empty() {
+            return None;
+        }
+        let slot = ((height + round as u64) % self.validators.len() as u64) as usize;
+        Some(&self.validators[slot])
+    }
+
+    // Rounds start when the parent was produced and last `round_timeout_s`,
+    // a block belongs to the round its timestamp falls in
+    fn round_at(&self, parent: &BlockHeader<BftProof>, timestamp: i64) -> u32 {
+        let elapsed = (timestamp - parent.timestamp).max(0);
+        (elapsed / self.round_timeout_s).min(u32::MAX as i64) as u32
+    }
+
+    // Key of this node, if it is one of the validators
+    fn voting_key(&self) -> Option<&SigningKey> {
+        let key = self.signing_key.as_ref()?;
+        self.validators
+            .contains(&keys::public_key_hex(key))
+            .then_some(key)
+    }
+
+    fn send(&self, messages: Vec<ConsensusMessage>) {
+        if let Some(outbox) = &self.outbox {
+            for message in messages {
+                let _ = outbox.send(message);
+            }
+        }
+    }
+
+    // Certificate this node saw finalizing the block hashed `hash`, only kept
+    // for the last decided block
+    fn certificate_for(&self, hash: &str) -> Option<CommitCertificate> {
+        let state = self.rounds.lock().unwrap();
+        match &state.last_commit {
+            Some((decided, certificate)) if decided == hash => Some(certificate.clone()),
+            _ => None,
+        }
+    }
+
+    // Counts the valid precommits of `certificate` for the block of `header`
+    fn verify_commit(
+        &self,
+        header: &BlockHeader<BftProof>,
+        certificate: Option<&CommitCertificate>,
+    ) -> Result<(), ValidationError> {
+        let signatures = certificate.map_or(0, |certificate| {
+            let message = vote_message(
+                VoteKind::Precommit,
+                header.index,
+                certificate.round,
+                &header.hash,
+            );
+            certificate
+                .precommits
+                .iter()
+                .filter(|(validator, signature)| {
+                    self.validators.contains(validator)
+                        && keys::verify(validator, &message, signature)
+                })
+                .count()
+        });
+        if signatures < self.quorum() {
+            return Err(ValidationError::InsufficientCommit {
+                index: header.index,
+                signatures,
+                quorum: self.quorum(),
+            });
+        }
+        Ok(())
+    }
+
+    fn cast(
+        &self,
+        state: &mut RoundState,
+        key: &SigningKey,
+        kind: VoteKind,
+        round: u32,
+        block_hash: &str,
+        outgoing: &mut Vec<ConsensusMessage>,
+    ) {
+        let validator = keys::public_key_hex(key);
+        let signature = keys::sign(key, &vote_message(kind, state.height, round, block_hash));
+        state.voted.insert((kind, round));
+        state
+            .votes
+            .entry((kind, round, block_hash.to_string()))
+            .or_default()
+            .insert(validator.clone(), signature.clone());
+        outgoing.push(ConsensusMessage::Vote(Vote {
+            kind,
+            height: state.height,
+            round,
+            block_hash: block_hash.to_string(),
+            validator,
+            signature,
+        }));
+    }
+
+    // Casts the votes the current state calls for and returns the block once
+    // two thirds of the validators precommitted to it
+    fn step(
+        &self,
+        state: &mut RoundState,
+        outgoing: &mut Vec<ConsensusMessage>,
+    ) -> Option<Block<BftProof>> {
+        let key = self.voting_key();
+
+        if let Some(key) = key {
+            let proposals: Vec<(u32, String)> = state
+                .proposals
+                .iter()
+                .map(|(round, block)| (*round, block.header.hash.clone()))
+                .collect();
+            for (round, hash) in proposals {
+                let free = state
+                    .locked
+                    .as_ref()
+                    .is_none_or(|(_, locked)| *locked == hash);
+                if free && !state.voted.contains(&(VoteKind::Prevote, round)) {
+                    self.cast(state, key, VoteKind::Prevote, round, &hash, outgoing);
+                }
+            }
+        }
+
+        for (round, hash) in state.quorums(VoteKind::Prevote, self.quorum()) {
+            if state.proposed(&hash).is_none() {
+                continue;
+            }
+            if state
+                .locked
+                .as_ref()
+                .is_none_or(|(locked, _)| round >= *locked)
+            {
+                state.locked = Some((round, hash.clone()));
+            }
+            let locked_here = state.locked.as_ref() == Some(&(round, hash.clone()));
+            if let Some(key) = key {
+                if locked_here && !state.voted.contains(&(VoteKind::Precommit, round)) {
+                    self.cast(state, key, VoteKind::Precommit, round, &hash, outgoing);
+                }
+            }
+        }
+
+        if state.decided {
+            return None;
+        }
+        for (round, hash) in state.quorums(VoteKind::Precommit, self.quorum()) {
+            let Some(block) = state.proposed(&hash).cloned() else {
+                continue;
+            };
+            let precommits = state.votes[&(VoteKind::Precommit, round, hash.clone())].clone();
+            let certificate = CommitCertificate { round, precommits };
+            state.decided = true;
+            state.last_commit = Some((hash, certificate.clone()));
+            outgoing.push(ConsensusMessage::Commit(Box::new(Commit {
+                block: block.clone(),
+                certificate,
+            })));
+            return Some(block);
+        }
+        None
+    }
+}
+
+impl fmt::Display for BftFinality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Validators = {}, quorum = {}, round timeout = {}s",
+            self.validators.len(),
+            self.quorum(),
+            self.round_timeout_s
+        )
+    }
+}
+
+impl Consensus for BftFinality {
+    type Proof = BftProof;
+
+    // Proposes a block when this node's turn comes. The block is only final
+    // once the votes on it arrive, so the proof is returned right away only
+    // when this node alone makes a quorum.
+    fn prove<'a>(
+        &'a self,
+        previous_headers: &'a [BlockHeader<Self::Proof>],
+        timestamp: i64,
+        body: &'a BlockBody,
+        _cancel: CancellationToken,
+    ) -> Pin<Box<dyn Future<Output = Option<Self::Proof>> + Send + 'a>> {
+        Box::pin(async move {
+            let key = self.voting_key()?;
+            let parent = previous_headers.last()?;
+            let (next_index, previous_hash) = next_position(previous_headers);
+            let round = self.round_at(parent, timestamp);
+            let proposer = keys::public_key_hex(key);
+            if self.proposer_for(next_index, round) != Some(proposer.as_str()) {
+                return None;
+            }
+
+            let mut state = self.rounds.lock().unwrap();
+            state.advance_to(parent, self.quorum());
+            if state.decided || state.proposals.contains_key(&round) {
+                return None;
+            }
+
+            let block = match state.locked_block() {
+                Some(locked) => locked.clone(),
+                None => {
+                    // Blocks after genesis carry the certificate finalizing their parent
+                    let parent_commit = match &state.last_commit {
+                        _ if parent.proof.proposer.is_empty() => None,
+                        Some((hash, certificate)) if *hash == parent.hash => {
+                            Some(certificate.clone())
+                        }
+                        _ => return None,
+                    };
+                    let seal = seal_hash(
+                        self.rules.hash,
+                        BLOCK_VERSION,
+                        next_index,
+                        timestamp,
+                        &body.merkle_root(self.rules.hash),
+                        previous_hash,
+                        &proposer,
+                    );
+                    let proof = BftProof {
+                        proposer: proposer.clone(),
+                        signature: keys::sign(key, seal.as_bytes()),
+                        parent_commit,
+                    };
+                    Block::new(
+                        next_index,
+                        body.clone(),
+                        timestamp,
+                        proof,
+                        previous_hash.to_string(),
+                        self.rules.hash,
+                    )
+                }
+            };
+
+            let signature = keys::sign(key, &proposal_message(round, &block));
+            let mut outgoing = vec![ConsensusMessage::Proposal(Box::new(Proposal {
+                round,
+                block: block.clone(),
+                proposer,
+                signature,
+            }))];
+            state.proposals.insert(round, block);
+            let decided = self.step(&mut state, &mut outgoing);
+            drop(state);
+            self.send(outgoing);
+
+            decided
+                .filter(|block| {
+                    block.header.timestamp == timestamp
+                        && block.header.merkle_root == body.merkle_root(self.rules.hash)
+                })
+                .map(|block| block.header.proof)
+        })
+    }
+
+    fn rules(&self) -> &BlockRules {
+        &self.rules
+    }
+
+    fn set_rules(&mut self, rules: BlockRules) {
+        self.rules = rules;
+    }
+
+    fn genesis_proof(&self) -> Self::Proof {
+        BftProof::unsigned()
+    }
+
+    fn validate_header(
+        &self,
+        previous_headers: &[BlockHeader<Self::Proof>],
+        header: &BlockHeader<Self::Proof>,
+    ) -> Result<(), ValidationError> {
+        self.validate_common(previous_headers, header)?;
+
+        let proposer = &header.proof.proposer;
+        if !self.validators.contains(proposer) {
+            return Err(ValidationError::UnknownValidator {
+                validator: proposer.clone(),
+            });
+        }
+        // A block is sealed by the proposer of the round it was made in, a
+        // block locked on and proposed again later keeps its first proposer
+        if let Some(parent) = previous_headers.last() {
+            let round = self.round_at(parent, header.timestamp);
+            let expected = self.proposer_for(header.index, round);
+            if expected != Some(proposer.as_str()) {
+                return Err(ValidationError::WrongValidator {
+                    expected: expected.map(str::to_string),
+                    found: proposer.clone(),
+                });
+            }
+        }
+        let seal = seal_hash(
+            self.rules.hash,
+            header.version,
+            header.index,
+            header.timestamp,
+            &header.merkle_root,
+            &header.previous_hash,
+            proposer,
+        );
+        if !keys::verify(proposer, seal.as_bytes(), &header.proof.signature) {
+            return Err(ValidationError::BadSignature {
+                validator: proposer.clone(),
+            });
+        }
+
+        // Genesis, or the last block before an upgrade to this engine, is not
+        // voted on
+        match previous_headers.last() {
+            Some(parent) if !parent.proof.proposer.is_empty() => {
+                self.verify_commit(parent, header.proof.parent_commit.as_ref())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Records the message and votes in turn
+    fn handle_message(
+        &self,
+        previous_headers: &[BlockHeader<Self::Proof>],
+        on_chain: &HashSet<String>,
+        message: ConsensusMessage,
+    ) -> Result<Option<Block<Self::Proof>>, ValidationError> {
+        let parent = previous_headers
+            .last()
+            .ok_or(ValidationError::MissingParent)?;
+        let mut state = self.rounds.lock().unwrap();
+        state.advance_to(parent, self.quorum());
+
+        match message {
+            ConsensusMessage::Proposal(proposal) => {
+                // Proposals for other heights are stale or ahead of our chain
+                if proposal.block.header.index != state.height
+                    || state.proposals.contains_key(&proposal.round)
+                {
+                    return Ok(None);
+                }
+                let expected = self.proposer_for(state.height, proposal.round);
+                if expected != Some(proposal.proposer.as_str()) {
+                    return Err(ValidationError::WrongValidator {
+                        expected: expected.map(str::to_string),
+                        found: proposal.proposer,
+                    });
+                }
+                let message = proposal_message(proposal.round, &proposal.block);
+                if !keys::verify(&proposal.proposer, &message, &proposal.signature) {
+                    return Err(ValidationError::BadSignature {
+                        validator: proposal.proposer,
+                    });
+                }
+                self.validate_block(previous_headers, on_chain, &proposal.block)?;
+                state.proposals.insert(proposal.round, proposal.block);
+            }
+            ConsensusMessage::Vote(vote) => {
+                let for_parent = vote.kind == VoteKind::Precommit
+                    && vote.height + 1 == state.height
+                    && vote.block_hash == state.parent_hash;
+                if vote.height != state.height && !for_parent {
+                    return Ok(None);
+                }
+                if !self.validators.contains(&vote.validator) {
+                    return Err(ValidationError::UnknownValidator {
+                        validator: vote.validator,
+                    });
+                }
+                let message = vote_message(vote.kind, vote.height, vote.round, &vote.block_hash);
+                if !keys::verify(&vote.validator, &message, &vote.signature) {
+                    return Err(ValidationError::BadSignature {
+                        validator: vote.validator,
+                    });
+                }
+                if for_parent {
+                    state
+                        .parent_precommits
+                        .entry(vote.round)
+                        .or_default()
+                        .insert(vote.validator, vote.signature);
+                    state.certify_parent(self.quorum());
+                    return Ok(None);
+                }
+                state
+                    .votes
+                    .entry((vote.kind, vote.round, vote.block_hash))
+                    .or_default()
+                    .insert(vote.validator, vote.signature);
+            }
+            ConsensusMessage::Commit(commit) => {
+                // Commits for other heights are stale or ahead of our chain
+                if state.decided || commit.block.header.index != state.height {
+                    return Ok(None);
+                }
+                self.validate_block(previous_headers, on_chain, &commit.block)?;
+                self.verify_commit(&commit.block.header, Some(&commit.certificate))?;
+                let Commit { block, certificate } = *commit;
+                state.decided = true;
+                state.last_commit = Some((block.header.hash.clone(), certificate));
+                return Ok(Some(block));
+            }
+        }
+
+        let mut outgoing = Vec::new();
+        let decided = self.step(&mut state, &mut outgoing);
+        drop(state);
+        self.send(outgoing);
+        Ok(decided)
+    }
+
+    // Blocks are appended once decided by vote, never as broadcast by a peer
+    fn decides_by_vote(&self, _previous_headers: &[BlockHeader<Self::Proof>]) -> bool {
+        true
+    }
+
+    // The tip once this node saw its certificate, otherwise the newest block
+    // certified by its child
+    fn finalized_height(&self, headers: &[BlockHeader<Self::Proof>]) -> u64 {
+        let Some(tip) = headers.last() else {
+            return 0;
+        };
+        if self.certificate_for(&tip.hash).is_some() {
+            return tip.index;
+        }
+        headers
+            .iter()
+            .rev()
+            .find(|header| header.proof.parent_commit.is_some())
+            .map_or(0, |header| header.index - 1)
+    }
+
+    // Genesis and the last block before an upgrade are not voted on
+    fn tip_certified(&self, headers: &[BlockHeader<Self::Proof>]) -> bool {
+        let Some(tip) = headers.last() else {
+            return true;
+        };
+        tip.proof.proposer.is_empty() || self.certificate_for(&tip.hash).is_some()
+    }
+
+    fn tip_commit(&self, tip: &Block<Self::Proof>) -> Option<ConsensusMessage> {
+        let certificate = self.certificate_for(&tip.header.hash)?;
+        Some(ConsensusMessage::Commit(Box::new(Commit {
+            block: tip.clone(),
+            certificate,
+        })))
+    }
+
+    fn set_outbox(&mut self, outbox: UnboundedSender<ConsensusMessage>) {
+        self.outbox = Some(outbox);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::genesis::GENESIS_TIMESTAMP;
+    use crate::blockchain::GenesisConfig;
+
+    const ROUND_TIMEOUT_S: i64 = 10;
+
+    fn validator_keys() -> Vec<SigningKey> {
+        [0x11, 0x22, 0x33, 0x44]
+            .iter()
+            .map(|byte| SigningKey::from_bytes(&[*byte; 32]))
+            .collect()
+    }
+
+    // Four validators, voting with `key` if given
+    fn engine(validators: &[SigningKey], key: Option<&SigningKey>) -> BftFinality {
+        let mut engine = BftFinality::new(
+            BlockRules::default(),
+            validators.iter().map(keys::public_key_hex).collect(),
+            ROUND_TIMEOUT_S,
+        );
+        if let Some(key) = key {
+            engine.set_signing_key(key.clone());
+        }
+        engine
+    }
+
+    fn genesis(engine: &BftFinality) -> BlockHeader<BftProof> {
+        GenesisConfig::default().spec(engine).block().header
+    }
+
+    // Child of `parent` over an empty body, sealed by `key` whether or not it
+    // is its turn
+    fn sealed_block(
+        key: &SigningKey,
+        parent: &BlockHeader<BftProof>,
+        timestamp: i64,
+        parent_commit: Option<CommitCertificate>,
+    ) -> Block<BftProof> {
+        let hash = BlockRules::default().hash;
+        let body = BlockBody::default();
+        let index = parent.index + 1;
+        let proposer = keys::public_key_hex(key);
+        let seal = seal_hash(
+            hash,
+            BLOCK_VERSION,
+            index,
+            timestamp,
+            &body.merkle_root(hash),
+            &parent.hash,
+            &proposer,
+        );
+        let proof = BftProof {
+            signature: keys::sign(key, seal.as_bytes()),
+            proposer,
+            parent_commit,
+        };
+        Block::new(index, body, timestamp, proof, parent.hash.clone(), hash)
+    }
+
+    fn certificate(
+        signers: &[SigningKey],
+        header: &BlockHeader<BftProof>,
+        round: u32,
+    ) -> CommitCertificate {
+        let message = vote_message(VoteKind::Precommit, header.index, round, &header.hash);
+        let precommits = signers
+            .iter()
+            .map(|key| (keys::public_key_hex(key), keys::sign(key, &message)))
+            .collect();
+        CommitCertificate { round, precommits }
+    }
+
+    fn vote(
+        key: &SigningKey,
+        kind: VoteKind,
+        round: u32,
+        block: &Block<BftProof>,
+    ) -> ConsensusMessage {
+        let header = &block.header;
+        ConsensusMessage::Vote(Vote {
+            kind,
+            height: header.index,
+            round,
+            block_hash: header.hash.clone(),
+            validator: keys::public_key_hex(key),
+            signature: keys::sign(key, &vote_message(kind, header.index, round, &header.hash)),
+        })
+    }
+
+    fn proposal(key: &SigningKey, round: u32, block: &Block<BftProof>) -> ConsensusMessage {
+        ConsensusMessage::Proposal(Box::new(Proposal {
+            round,
+            block: block.clone(),
+            proposer: keys::public_key_hex(key),
+            signature: keys::sign(key, &proposal_message(round, block)),
+        }))
+    }
+
+    #[test]
+    fn consensus_messages_round_trip_their_encoding() {
+        let keys = validator_keys();
+        let consensus = engine(&keys, None);
+        let block = sealed_block(&keys[1], &genesis(&consensus), GENESIS_TIMESTAMP + 1, None);
+        let commit = ConsensusMessage::Commit(Box::new(Commit {
+            certificate: certificate(&keys[1..], &block.header, 0),
+            block: block.clone(),
+        }));
+        for message in [
+            proposal(&keys[1], 0, &block),
+            vote(&keys[2], VoteKind::Prevote, 0, &block),
+            vote(&keys[3], VoteKind::Precommit, 0, &block),
+            commit,
+        ] {
+            let encoding = message.encode();
+            let decoded = ConsensusMessage::decode(&encoding).unwrap();
+            assert_eq!(decoded.encode(), encoding);
+        }
+        assert_eq!(
+            ConsensusMessage::decode(&[3]).unwrap_err(),
+            DecodeError::UnknownTag {
+                kind: "consensus message",
+                tag: 3
+            }
+        );
+    }
+
+    #[test]
+    fn quorum_counts_distinct_valid_precommits_of_validators() {
+        let keys = validator_keys();
+        assert_eq!(engine(&keys[..1], None).quorum(), 1);
+        assert_eq!(engine(&keys[..3], None).quorum(), 3);
+        let consensus = engine(&keys, None);
+        assert_eq!(consensus.quorum(), 3);
+
+        let parent = genesis(&consensus);
+        let header = sealed_block(&keys[1], &parent, GENESIS_TIMESTAMP + 1, None).header;
+        let insufficient = |certificate: &CommitCertificate| match consensus
+            .verify_commit(&header, Some(certificate))
+        {
+            Err(ValidationError::InsufficientCommit { signatures, .. }) => Some(signatures),
+            _ => None,
+        };
+        assert!(consensus
+            .verify_commit(&header, Some(&certificate(&keys[..3], &header, 0)))
+            .is_ok());
+        assert_eq!(insufficient(&certificate(&keys[..2], &header, 0)), Some(2));
+
+        // A signature over another round, or by a key outside the validator
+        // set, does not count
+        let mut forged = certificate(&keys[..3], &header, 0);
+        let other_round = certificate(&keys[..1], &header, 1);
+        forged.precommits.extend(other_round.precommits);
+        assert_eq!(insufficient(&forged), Some(2));
+        let outsider = SigningKey::from_bytes(&[0x55; 32]);
+        let mut padded = certificate(&keys[..2], &header, 0);
+        padded
+            .precommits
+            .extend(certificate(&[outsider], &header, 0).precommits);
+        assert_eq!(insufficient(&padded), Some(2));
+
+        // Signatures for the round they claim only
+        let mut moved = certificate(&keys[..3], &header, 0);
+        moved.round = 1;
+        assert_eq!(insufficient(&moved), Some(0));
+        assert!(consensus.verify_commit(&header, None).is_err());
+    }
+
+    #[test]
+    fn header_must_come_from_the_proposer_of_its_round() {
+        let keys = validator_keys();
+        let consensus = engine(&keys, None);
+        let parent = genesis(&consensus);
+        let validate = |key: &SigningKey, timestamp: i64| {
+            let block = sealed_block(key, &parent, timestamp, None);
+            consensus.validate_header(std::slice::from_ref(&parent), &block.header)
+        };
+
+        // Height 1 is proposed by the second validator in round 0, by the
+        // third once the round timed out
+        assert!(validate(&keys[1], GENESIS_TIMESTAMP + 1).is_ok());
+        assert!(matches!(
+            validate(&keys[2], GENESIS_TIMESTAMP + 1),
+            Err(ValidationError::WrongValidator { expected: Some(expected), .. })
+                if expected == keys::public_key_hex(&keys[1])
+        ));
+        assert!(validate(&keys[2], GENESIS_TIMESTAMP + ROUND_TIMEOUT_S + 1).is_ok());
+        assert!(matches!(
+            validate(&SigningKey::from_bytes(&[0x55; 32]), GENESIS_TIMESTAMP + 1),
+            Err(ValidationError::UnknownValidator { .. })
+        ));
+    }
+
+    #[test]
+    fn child_must_certify_its_parent() {
+        let keys = validator_keys();
+        let consensus = engine(&keys, None);
+        let genesis = genesis(&consensus);
+        let parent = sealed_block(&keys[1], &genesis, GENESIS_TIMESTAMP + 1, None).header;
+        let history = [genesis, parent.clone()];
+        let child = |parent_commit| {
+            let block = sealed_block(&keys[2], &parent, GENESIS_TIMESTAMP + 2, parent_commit);
+            consensus.validate_header(&history, &block.header)
+        };
+
+        assert!(child(Some(certificate(&keys[1..], &parent, 0))).is_ok());
+        assert!(matches!(
+            child(Some(certificate(&keys[..2], &parent, 0))),
+            Err(ValidationError::InsufficientCommit { signatures: 2, .. })
+        ));
+        assert!(matches!(
+            child(None),
+            Err(ValidationError::InsufficientCommit { signatures: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn locks_on_prevoted_block_until_a_later_round_prevotes_another() {
+        let keys = validator_keys();
+        let consensus = engine(&keys, Some(&keys[0]));
+        let genesis = genesis(&consensus);
+        let history = std::slice::from_ref(&genesis);
+        let on_chain = HashSet::new();
+        let handle = |message| consensus.handle_message(history, &on_chain, message);
+
+        // Round 0: two thirds prevote the first proposal, this node locks on
+        // it and precommits
+        let first = sealed_block(&keys[1], &genesis, GENESIS_TIMESTAMP + 1, None);
+        handle(proposal(&keys[1], 0, &first)).unwrap();
+        for key in &keys[1..3] {
+            handle(vote(key, VoteKind::Prevote, 0, &first)).unwrap();
+        }
+        {
+            let state = consensus.rounds.lock().unwrap();
+            assert_eq!(state.locked, Some((0, first.header.hash.clone())));
+            assert!(state.voted.contains(&(VoteKind::Precommit, 0)));
+        }
+
+        // Round 1: locked, this node does not prevote another block
+        let second = sealed_block(
+            &keys[2],
+            &genesis,
+            GENESIS_TIMESTAMP + ROUND_TIMEOUT_S + 1,
+            None,
+        );
+        handle(proposal(&keys[2], 1, &second)).unwrap();
+        assert!(!consensus
+            .rounds
+            .lock()
+            .unwrap()
+            .voted
+            .contains(&(VoteKind::Prevote, 1)));
+
+        // Until two thirds prevote it, which unlocks and moves the lock over
+        for key in &keys[1..] {
+            handle(vote(key, VoteKind::Prevote, 1, &second)).unwrap();
+        }
+        {
+            let state = consensus.rounds.lock().unwrap();
+            assert_eq!(state.locked, Some((1, second.header.hash.clone())));
+            assert!(state.voted.contains(&(VoteKind::Precommit, 1)));
+        }
+
+        handle(vote(&keys[2], VoteKind::Precommit, 1, &second)).unwrap();
+        let decided = handle(vote(&keys[3], VoteKind::Precommit, 1, &second)).unwrap();
+        assert_eq!(decided.unwrap().header.hash, second.header.hash);
+    }
+
+    #[test]
+    fn tip_is_only_certified_once_its_commit_was_seen() {
+        let keys = validator_keys();
+        let consensus = engine(&keys, None);
+        let genesis = genesis(&consensus);
+        let history = std::slice::from_ref(&genesis);
+        let block = sealed_block(&keys[1], &genesis, GENESIS_TIMESTAMP + 1, None);
+        let chain = [genesis.clone(), block.header.clone()];
+        let commit = |signers: &[SigningKey]| {
+            ConsensusMessage::Commit(Box::new(Commit {
+                block: block.clone(),
+                certificate: certificate(signers, &block.header, 0),
+            }))
+        };
+
+        // Genesis is not voted on
+        assert!(consensus.tip_certified(history));
+        assert!(!consensus.tip_certified(&chain));
+        assert_eq!(consensus.finalized_height(&chain), 0);
+
+        assert!(matches!(
+            consensus.handle_message(history, &HashSet::new(), commit(&keys[..2])),
+            Err(ValidationError::InsufficientCommit { .. })
+        ));
+        assert!(!consensus.tip_certified(&chain));
+        assert!(consensus.tip_commit(&block).is_none());
+
+        let decided = consensus
+            .handle_message(history, &HashSet::new(), commit(&keys[1..]))
+            .unwrap();
+        assert!(decided.is_some());
+        assert!(consensus.tip_certified(&chain));
+        assert_eq!(consensus.finalized_height(&chain), 1);
+        assert!(matches!(
+            consensus.tip_commit(&block),
+            Some(ConsensusMessage::Commit(commit)) if commit.certificate.precommits.len() == 3
+        ));
+    }
+}
@@ -1,31 +1,60 @@
+use super::{GenesisData, MessageTransaction};
+use crate::utils::encoding::{Decode, DecodeError, Decoder, Encode};
+use crate::utils::merkle::{self, ProofStep};
+use crate::utils::HashAlgorithm;
 use chrono::{DateTime, Utc};
-use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
+/// Version of the blocks this node produces. A new version may change what
+/// goes into a block and its hash, and is validated by its own rules.
+/// Version 2 transactions carry their sender and signature.
+pub const BLOCK_VERSION: u32 = 2;
+
+/// Block versions this node can validate, advertised to clients and peers.
+pub const SUPPORTED_BLOCK_VERSIONS: [u32; 1] = [BLOCK_VERSION];
+
+/// Everything a block's hash and proof commit to. The transactions only enter
+/// through their Merkle root, so headers alone are enough to follow a chain
+/// and check its proofs.
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Block<P> {
+pub struct BlockHeader<P> {
+    /// Layout and validation rules the block follows, committed to by its hash
+    pub version: u32,
     pub index: u64,
     pub timestamp: i64,
-    pub data: String,
+    /// Root of the Merkle tree over the transactions in the body
+    pub merkle_root: String,
     pub previous_hash: String,
     pub hash: String,
     pub proof: P,
 }
 
-impl<P: Clone + Serialize + DeserializeOwned> Block<P> {
+impl<P: Encode> BlockHeader<P> {
+    /// Header of the current `BLOCK_VERSION` over `merkle_root`, with its
+    /// hash computed.
     pub fn new(
         index: u64,
-        data: String,
         timestamp: i64,
-        proof: P,
+        merkle_root: String,
         previous_hash: String,
-    ) -> Block<P> {
-        let hash = crate::utils::hash(index, timestamp, &data, &previous_hash, &proof);
+        proof: P,
+        hash_algorithm: HashAlgorithm,
+    ) -> BlockHeader<P> {
+        let hash = crate::utils::hash(
+            hash_algorithm,
+            BLOCK_VERSION,
+            index,
+            timestamp,
+            &merkle_root,
+            &previous_hash,
+            &proof,
+        );
 
-        Block {
+        BlockHeader {
+            version: BLOCK_VERSION,
             index,
-            data,
             timestamp,
+            merkle_root,
             previous_hash,
             hash,
             proof,
@@ -33,7 +62,20 @@ impl<P: Clone + Serialize + DeserializeOwned> Block<P> {
     }
 }
 
-impl<P> Block<P> {
+impl<P> BlockHeader<P> {
+    /// The same header carrying `proof`, for moving between proof types.
+    pub fn with_proof<Q>(&self, proof: Q) -> BlockHeader<Q> {
+        BlockHeader {
+            version: self.version,
+            index: self.index,
+            timestamp: self.timestamp,
+            merkle_root: self.merkle_root.clone(),
+            previous_hash: self.previous_hash.clone(),
+            hash: self.hash.clone(),
+            proof,
+        }
+    }
+
     pub fn formatted_timestamp(&self) -> String {
         match DateTime::<Utc>::from_timestamp(self.timestamp, 0) {
             Some(date) => date.to_string(),
@@ -41,3 +83,279 @@ impl<P> Block<P> {
         }
     }
 }
+
+/// Payload of a block, committed to by the Merkle root of its header.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct BlockBody {
+    pub transactions: Vec<MessageTransaction>,
+    /// What the network was started with, only carried by the genesis block
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub genesis: Option<GenesisData>,
+}
+
+impl BlockBody {
+    pub fn new(transactions: Vec<MessageTransaction>) -> Self {
+        BlockBody {
+            transactions,
+            genesis: None,
+        }
+    }
+
+    pub fn transaction_ids(&self) -> Vec<String> {
+        self.transactions.iter().map(|tx| tx.id.clone()).collect()
+    }
+
+    /// Merkle root the header of a block carrying this body commits to.
+    pub fn merkle_root(&self, hash_algorithm: HashAlgorithm) -> String {
+        hex::encode(merkle::root(hash_algorithm, &self.leaves(hash_algorithm)))
+    }
+
+    // The genesis data, if any, then one leaf per transaction, each over its
+    // canonical encoding
+    fn leaves(&self, hash_algorithm: HashAlgorithm) -> Vec<[u8; 32]> {
+        let genesis = self.genesis.as_ref().map(Encode::encode);
+        let transactions = self.transactions.iter().map(Encode::encode);
+        genesis
+            .into_iter()
+            .chain(transactions)
+            .map(|bytes| merkle::leaf_hash(hash_algorithm, &bytes))
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Block<P> {
+    pub header: BlockHeader<P>,
+    pub body: BlockBody,
+}
+
+impl<P: Encode> Block<P> {
+    pub fn new(
+        index: u64,
+        body: BlockBody,
+        timestamp: i64,
+        proof: P,
+        previous_hash: String,
+        hash_algorithm: HashAlgorithm,
+    ) -> Block<P> {
+        let header = BlockHeader::new(
+            index,
+            timestamp,
+            body.merkle_root(hash_algorithm),
+            previous_hash,
+            proof,
+            hash_algorithm,
+        );
+
+        Block { header, body }
+    }
+}
+
+impl<P> Block<P> {
+    /// The same block carrying `proof`, for moving between proof types.
+    pub fn with_proof<Q>(&self, proof: Q) -> Block<Q> {
+        Block {
+            header: self.header.with_proof(proof),
+            body: self.body.clone(),
+        }
+    }
+
+    /// Proof that the transaction `transaction_id` is part of this block.
+    pub fn inclusion_proof(
+        &self,
+        hash_algorithm: HashAlgorithm,
+        transaction_id: &str,
+    ) -> Option<InclusionProof> {
+        let position = self
+            .body
+            .transactions
+            .iter()
+            .position(|tx| tx.id == transaction_id)?;
+        // Transaction leaves follow the genesis data leaf, if there is one
+        let leaf_index = position + self.body.genesis.is_some() as usize;
+        let leaves = self.body.leaves(hash_algorithm);
+        Some(InclusionProof {
+            block_index: self.header.index,
+            block_hash: self.header.hash.clone(),
+            merkle_root: self.header.merkle_root.clone(),
+            leaf: hex::encode(leaves[leaf_index]),
+            path: merkle::proof(hash_algorithm, &leaves, leaf_index)?,
+            transaction: self.body.transactions[position].clone(),
+        })
+    }
+}
+
+/// Shows a transaction is in a block: hashing `leaf` with each sibling of
+/// `path` in turn yields the block's `merkle_root`.
+#[derive(Debug, Clone, Serialize)]
+pub struct InclusionProof {
+    pub block_index: u64,
+    pub block_hash: String,
+    pub merkle_root: String,
+    pub transaction: MessageTransaction,
+    /// Leaf hash of the canonically encoded transaction
+    pub leaf: String,
+    pub path: Vec<ProofStep>,
+}
+
+// A header is its hash preimage followed by the hash
+impl<P: Encode> Encode for BlockHeader<P> {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.extend(crate::utils::header_preimage(
+            self.version,
+            self.index,
+            self.timestamp,
+            &self.merkle_root,
+            &self.previous_hash,
+            &self.proof,
+        ));
+        self.hash.encode_to(out);
+    }
+}
+
+impl<P: Decode> Decode for BlockHeader<P> {
+    fn decode_from(input: &mut Decoder<'_>) -> Result<Self, DecodeError> {
+        input.expect_version()?;
+        Ok(BlockHeader {
+            version: Decode::decode_from(input)?,
+            index: Decode::decode_from(input)?,
+            timestamp: Decode::decode_from(input)?,
+            merkle_root: Decode::decode_from(input)?,
+            previous_hash: Decode::decode_from(input)?,
+            proof: Decode::decode_from(input)?,
+            hash: Decode::decode_from(input)?,
+        })
+    }
+}
+
+impl Encode for BlockBody {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.transactions.encode_to(out);
+        self.genesis.encode_to(out);
+    }
+}
+
+impl Decode for BlockBody {
+    fn decode_from(input: &mut Decoder<'_>) -> Result<Self, DecodeError> {
+        Ok(BlockBody {
+            transactions: Decode::decode_from(input)?,
+            genesis: Decode::decode_from(input)?,
+        })
+    }
+}
+
+impl<P: Encode> Encode for Block<P> {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.header.encode_to(out);
+        self.body.encode_to(out);
+    }
+}
+
+impl<P: Decode> Decode for Block<P> {
+    fn decode_from(input: &mut Decoder<'_>) -> Result<Self, DecodeError> {
+        Ok(Block {
+            header: Decode::decode_from(input)?,
+            body: Decode::decode_from(input)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::engine::EngineProof;
+    use serde::de::DeserializeOwned;
+    use serde_json::Value;
+
+    const VECTORS: &str = include_str!("../../tests/encoding_vectors.json");
+
+    fn field<'a>(vector: &'a Value, name: &str) -> &'a str {
+        vector[name]
+            .as_str()
+            .unwrap_or_else(|| panic!("{}: no {}", vector["name"], name))
+    }
+
+    // Checks `value` encodes to the vector's encoding, which decodes back to
+    // a value with that same encoding, and returns the decoded value
+    fn round_trip<T: Encode + Decode + DeserializeOwned>(vector: &Value) -> T {
+        let name = &vector["name"];
+        let value: T = serde_json::from_value(vector["value"].clone()).unwrap();
+        let encoding = field(vector, "encoding");
+        assert_eq!(hex::encode(value.encode()), encoding, "{}", name);
+
+        let decoded = T::decode(&hex::decode(encoding).unwrap()).unwrap();
+        assert_eq!(hex::encode(decoded.encode()), encoding, "{}", name);
+        decoded
+    }
+
+    #[test]
+    fn encoding_vectors_round_trip() {
+        let vectors: Value = serde_json::from_str(VECTORS).unwrap();
+        let hash_algorithm = HashAlgorithm::Sha256;
+        for vector in vectors["vectors"].as_array().unwrap() {
+            let name = &vector["name"];
+            match field(vector, "kind") {
+                "transaction" => {
+                    let tx: MessageTransaction = round_trip(vector);
+                    assert_eq!(
+                        hex::encode(tx.signing_payload()),
+                        field(vector, "signing_payload"),
+                        "{}",
+                        name
+                    );
+                }
+                "genesis_data" => {
+                    round_trip::<GenesisData>(vector);
+                }
+                "header" => {
+                    let header: BlockHeader<EngineProof> = round_trip(vector);
+                    let preimage = crate::utils::header_preimage(
+                        header.version,
+                        header.index,
+                        header.timestamp,
+                        &header.merkle_root,
+                        &header.previous_hash,
+                        &header.proof,
+                    );
+                    assert_eq!(hex::encode(preimage), field(vector, "preimage"), "{}", name);
+                    let hash = crate::utils::hash(
+                        hash_algorithm,
+                        header.version,
+                        header.index,
+                        header.timestamp,
+                        &header.merkle_root,
+                        &header.previous_hash,
+                        &header.proof,
+                    );
+                    assert_eq!(hash, field(vector, "hash"), "{}", name);
+                    assert_eq!(header.hash, hash, "{}", name);
+                }
+                "block" => {
+                    let block: Block<EngineProof> = round_trip(vector);
+                    let merkle_root = block.body.merkle_root(hash_algorithm);
+                    assert_eq!(merkle_root, field(vector, "merkle_root"), "{}", name);
+                    assert_eq!(block.header.merkle_root, merkle_root, "{}", name);
+                }
+                kind => panic!("{}: unknown kind {}", name, kind),
+            }
+        }
+    }
+
+    #[test]
+    fn header_of_retired_encoding_is_refused() {
+        let vectors: Value = serde_json::from_str(VECTORS).unwrap();
+        let vector = vectors["vectors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|vector| vector["name"] == "genesis block header")
+            .unwrap();
+        let mut bytes = hex::decode(field(vector, "encoding")).unwrap();
+        // Encoding version 2, from before transactions were signed
+        bytes[0] = 2;
+        assert_eq!(
+            BlockHeader::<EngineProof>::decode(&bytes).unwrap_err(),
+            DecodeError::UnsupportedVersion { version: 2 }
+        );
+    }
+}
@@ -1,8 +1,12 @@
-use super::{Block, Consensus, Mempool, MessageTransaction};
-use chrono::Utc;
+use super::{
+    Block, BlockBody, BlockHeader, Consensus, FinalityRules, GenesisConfig, Mempool,
+    MessageTransaction, ValidationError,
+};
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::fs::File;
+use std::io::ErrorKind;
+use tokio_util::sync::CancellationToken;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(bound(deserialize = "C: Consensus"))]
@@ -11,11 +15,22 @@ where
     C: Consensus,
     C::Proof: Serialize + for<'b> Deserialize<'b>,
 {
+    /// Stored canonically encoded, like blocks travel between nodes
+    #[serde(with = "crate::utils::encoding::hex_encoded")]
     pub chain: Vec<Block<C::Proof>>,
     pub nodes: HashSet<String>,
+    /// Hash of the network's genesis block, chains starting elsewhere are rejected
+    pub genesis_hash: String,
 
     pub consensus: C,
     pub mempool: Mempool,
+    /// Taken from the genesis config on every start
+    #[serde(skip)]
+    pub finality: FinalityRules,
+    /// Ids of every transaction in `chain`, kept up to date as blocks are
+    /// added so submissions need not walk the whole chain
+    #[serde(skip)]
+    transaction_ids: HashSet<String>,
 }
 
 impl<C> Chain<C>
@@ -23,84 +38,140 @@ where
     C: Consensus,
     C::Proof: Serialize + for<'b> Deserialize<'b>,
 {
-    pub async fn new(consensus: C) -> Self {
-        let mut blockchain = Chain {
+    pub fn new(consensus: C, genesis: &GenesisConfig) -> Self {
+        let genesis_block = genesis.spec(&consensus).block();
+        let genesis_hash = genesis_block.header.hash.clone();
+        let mut chain = Self::from_blocks(vec![genesis_block], genesis_hash, consensus);
+        chain.mempool = Mempool::new(2, 100);
+        chain.finality = genesis.finality.clone();
+        chain
+    }
+
+    /// Chain of `blocks` starting from the genesis block hashed
+    /// `genesis_hash`, with no peers and an empty mempool.
+    pub fn from_blocks(blocks: Vec<Block<C::Proof>>, genesis_hash: String, consensus: C) -> Self {
+        let mut chain = Chain {
             chain: Vec::new(),
             nodes: HashSet::new(),
+            genesis_hash,
             consensus,
-            mempool: Mempool::new(2, 100),
+            mempool: Mempool::new(10, 100),
+            finality: Default::default(),
+            transaction_ids: HashSet::new(),
         };
-
-        // Genesis Block
-        let genesis_data = "Fiat Lux".to_string();
-        let timestamp = Utc::now().timestamp();
-        let index = 0;
-        let previous_hash = "0".to_string();
-
-        let genesis_proof = blockchain
-            .consensus
-            .prove(index, timestamp, &genesis_data, &previous_hash)
-            .await;
-        let genesis_block = Block::new(
-            0,
-            genesis_data,
-            Utc::now().timestamp(),
-            genesis_proof,
-            "0".to_string(),
-        );
-        blockchain.chain.push(genesis_block);
-
-        blockchain
+        chain.replace_blocks(blocks);
+        chain
     }
 
+    #[allow(dead_code)]
     pub fn submit_message_to_mempool(
         &mut self,
-        message: String,
+        transaction: MessageTransaction,
     ) -> Result<MessageTransaction, String> {
-        self.mempool.add_message(message)
+        self.mempool
+            .add_message(transaction, self.consensus.rules(), &self.transaction_ids)
     }
 
     pub async fn new_block(&mut self, timestamp: i64) -> Option<Block<C::Proof>> {
-        let max_messages = 10;
-        let messages = self.mempool.get_pending_messages(max_messages);
+        let messages = self
+            .mempool
+            .get_block_messages(self.consensus.rules(), &self.transaction_ids);
         if messages.is_empty() {
             return None;
         }
 
-        let data = serde_json::to_string(&messages).unwrap_or_default();
-        let prev_block = self.chain.last().unwrap();
-        let prev_hash = prev_block.hash.clone();
+        let body = BlockBody::new(messages);
+        let prev_hash = self.chain.last().unwrap().header.hash.clone();
         let index = self.chain.len() as u64;
+        let recent_headers = self.recent_headers();
+        let timestamp = timestamp.max(self.consensus.min_timestamp(&recent_headers));
 
         let proof = self
             .consensus
-            .prove(index, timestamp, &data, &prev_hash)
-            .await;
-
-        let block = Block::new(index, data, timestamp, proof, prev_hash);
+            .prove(&recent_headers, timestamp, &body, CancellationToken::new())
+            .await?;
+
+        let block = Block::new(
+            index,
+            body,
+            timestamp,
+            proof,
+            prev_hash,
+            self.consensus.rules().hash,
+        );
 
-        let message_ids: Vec<String> = messages.iter().map(|tx| tx.id.clone()).collect();
-        self.mempool.remove_messages(&message_ids);
-        self.chain.push(block.clone());
+        self.mempool.remove_messages(&block.body.transaction_ids());
+        self.push_block(block.clone());
 
         Some(block)
     }
 
+    /// Appends `block`, which has to extend the chain.
+    pub fn push_block(&mut self, block: Block<C::Proof>) {
+        self.transaction_ids.extend(block.body.transaction_ids());
+        self.chain.push(block);
+    }
+
+    /// Makes `blocks` the chain, as when adopting a peer's chain.
+    pub fn replace_blocks(&mut self, blocks: Vec<Block<C::Proof>>) {
+        self.chain = blocks;
+        self.index_transactions();
+    }
+
+    // Rebuilds the transaction id index from the blocks
+    fn index_transactions(&mut self) {
+        self.transaction_ids = self
+            .chain
+            .iter()
+            .flat_map(|block| block.body.transaction_ids())
+            .collect();
+    }
+
+    /// Headers of every block, enough to validate the chain's proofs and weigh it.
+    pub fn headers(&self) -> Vec<BlockHeader<C::Proof>> {
+        self.chain
+            .iter()
+            .map(|block| block.header.clone())
+            .collect()
+    }
+
+    /// Headers of the blocks consensus rules look back over when validating or
+    /// producing the next block.
+    pub fn recent_headers(&self) -> Vec<BlockHeader<C::Proof>> {
+        let window_start = self
+            .chain
+            .len()
+            .saturating_sub(self.consensus.history_len());
+        self.chain[window_start..]
+            .iter()
+            .map(|block| block.header.clone())
+            .collect()
+    }
+
+    /// Ids of every transaction in the chain.
+    pub fn transaction_ids(&self) -> &HashSet<String> {
+        &self.transaction_ids
+    }
+
     pub fn add_node(&mut self, address: &str) {
         self.nodes.insert(address.to_owned());
     }
+    #[allow(dead_code)]
     pub fn remove_node(&mut self, address: &String) {
         self.nodes.remove(address);
     }
+    #[allow(dead_code)]
     pub fn is_valid(&self) -> bool {
-        for i in 1..self.chain.len() {
-            let prev = &self.chain[i - 1];
-            let curr = &self.chain[i];
-            if curr.previous_hash != prev.hash || !self.consensus.validate_block(prev, curr) {
-                return false;
-            }
-        }
-        true
+        self.consensus.validate_chain(self).is_ok()
+    }
+
+    /// Checks adopting `candidate` instead of this chain reverts no final
+    /// block, whether finalized by the consensus or by the finality rules.
+    pub fn check_reorg(&self, candidate: &[BlockHeader<C::Proof>]) -> Result<(), ValidationError> {
+        let headers = self.headers();
+        let finalized_height = self.consensus.finalized_height(&headers);
+        self.finality
+            .check_reorg(&headers, candidate, finalized_height)
     }
 
     pub fn save_to_file(&self, path: &str) -> std::io::Result<()> {
@@ -109,19 +180,51 @@ where
         Ok(())
     }
 
+    #[allow(dead_code)]
     pub fn load_from_file(path: &str) -> std::io::Result<Self> {
         let file = File::open(path)?;
-        let chain: Chain<C> = serde_json::from_reader(file)?;
+        let mut chain: Chain<C> = serde_json::from_reader(file)?;
+        chain.index_transactions();
         Ok(chain)
     }
 
-    pub async fn load_or_create(path: &str, consensus: C) -> Self {
-        match File::open(path) {
-            Ok(file) => match serde_json::from_reader(file) {
-                Ok(chain) => chain,
-                Err(_) => Self::new(consensus).await,
-            },
-            Err(_) => Self::new(consensus).await,
+    /// Loads the chain in `path`, keeping the consensus engine it was created
+    /// with but following the block rules of `genesis`, or starts a new chain
+    /// with `consensus` if there is none. A file that cannot be read or
+    /// decoded is left alone and reported rather than being replaced.
+    pub fn load_or_create(
+        path: &str,
+        consensus: C,
+        genesis: &GenesisConfig,
+    ) -> Result<Self, String> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::new(consensus, genesis)),
+            Err(e) => return Err(format!("Failed to open chain file {}: {}", path, e)),
+        };
+        let mut chain = serde_json::from_reader::<_, Self>(file).map_err(|e| {
+            format!(
+                "Failed to decode chain file {}, written by another version of the node? \
+                 Move it away to start a new chain: {}",
+                path, e
+            )
+        })?;
+        chain.consensus.set_rules(genesis.rules.clone());
+        // An engine fixed by the genesis config has to seal the genesis block
+        // too, otherwise the stored engine does
+        let genesis_hash = if genesis.consensus.is_some() {
+            genesis.spec(&consensus).block().header.hash
+        } else {
+            genesis.spec(&chain.consensus).block().header.hash
+        };
+        if chain.genesis_hash != genesis_hash {
+            return Err(format!(
+                "Chain file {} starts from genesis {}, but the genesis config yields {}",
+                path, chain.genesis_hash, genesis_hash
+            ));
         }
+        chain.finality = genesis.finality.clone();
+        chain.index_transactions();
+        Ok(chain)
     }
 }
@@ -1,130 +1,1540 @@
-use super::{Block, Chain};
+use super::block::{BLOCK_VERSION, SUPPORTED_BLOCK_VERSIONS};
+use super::difficulty;
+use super::mining::RECENT_BLOCKS_WINDOW;
+use super::{Block, BlockBody, BlockHeader, Chain, ConsensusMessage, ValidationError};
+use crate::utils::encoding::{Decode, DecodeError, Decoder, Encode};
+use crate::utils::{keys, HashAlgorithm};
+use chrono::Utc;
 use core::fmt;
+use ed25519_dalek::SigningKey;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
 use std::fmt::{Debug, Display};
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_util::sync::CancellationToken;
+
+/// Network wide rules every block has to follow, whatever the consensus engine.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockRules {
+    /// Blocks must be newer than the median timestamp of this many ancestors
+    pub median_time_span: usize,
+    /// How far ahead of local time a block may be dated, in seconds
+    pub max_future_drift_s: i64,
+    /// Hash function for block hashes and proof of work
+    #[serde(default)]
+    pub hash: HashAlgorithm,
+    /// Most transactions a block may carry
+    #[serde(default = "default_max_block_transactions")]
+    pub max_block_transactions: usize,
+    /// Largest canonical encoding of a block body, in bytes. Headers are not
+    /// counted, their size is bounded by the consensus engine.
+    #[serde(default = "default_max_block_bytes")]
+    pub max_block_bytes: usize,
+}
+
+impl Default for BlockRules {
+    fn default() -> Self {
+        BlockRules {
+            median_time_span: 11,
+            max_future_drift_s: 120,
+            hash: HashAlgorithm::default(),
+            max_block_transactions: default_max_block_transactions(),
+            max_block_bytes: default_max_block_bytes(),
+        }
+    }
+}
+
+fn default_max_block_transactions() -> usize {
+    10
+}
+
+fn default_max_block_bytes() -> usize {
+    64 * 1024
+}
 
 pub trait Consensus:
     Sized + Clone + Send + Sync + 'static + Serialize + for<'a> Deserialize<'a>
 {
-    type Proof: Debug + Sync + Clone + Serialize + DeserializeOwned + Display + Send;
+    type Proof: Debug
+        + Sync
+        + Clone
+        + Serialize
+        + DeserializeOwned
+        + Encode
+        + Decode
+        + Display
+        + Send;
+
+    /// Produces a proof for the block carrying `body` that follows
+    /// `previous_headers`, or `None` if this node is not entitled to produce
+    /// it or `cancel` was triggered before a proof was found.
+    ///
+    /// `previous_headers` is a suffix of the chain ending at the parent block,
+    /// empty when proving the genesis block.
     fn prove<'a>(
         &'a self,
-        next_index: u64,
+        previous_headers: &'a [BlockHeader<Self::Proof>],
         timestamp: i64,
-        data: &'a str,
-        previous_hash: &'a str,
-    ) -> Pin<Box<dyn Future<Output = Self::Proof> + Send + 'a>>;
+        body: &'a BlockBody,
+        cancel: CancellationToken,
+    ) -> Pin<Box<dyn Future<Output = Option<Self::Proof>> + Send + 'a>>;
+
+    /// Validates `header` on top of `previous_headers`, a suffix of the chain
+    /// ending at its parent. Needs no block body, so light clients can follow
+    /// the chain from headers alone.
+    fn validate_header(
+        &self,
+        previous_headers: &[BlockHeader<Self::Proof>],
+        header: &BlockHeader<Self::Proof>,
+    ) -> Result<(), ValidationError>;
+
+    fn rules(&self) -> &BlockRules;
+
+    /// Replaces the block rules, which the genesis config defines for the
+    /// whole network.
+    fn set_rules(&mut self, rules: BlockRules);
+
+    /// How many of the latest blocks the engine looks back over to validate
+    /// or produce the next one, enough for every rule it applies.
+    fn history_len(&self) -> usize {
+        RECENT_BLOCKS_WINDOW.max(self.rules().median_time_span)
+    }
+
+    /// Earliest timestamp a block following `previous_headers` may carry,
+    /// one second past the median of the last `median_time_span` blocks.
+    fn min_timestamp(&self, previous_headers: &[BlockHeader<Self::Proof>]) -> i64 {
+        let span = previous_headers.len().min(self.rules().median_time_span);
+        if span == 0 {
+            return i64::MIN;
+        }
+        let mut timestamps: Vec<i64> = previous_headers[previous_headers.len() - span..]
+            .iter()
+            .map(|header| header.timestamp)
+            .collect();
+        timestamps.sort_unstable();
+        timestamps[span / 2] + 1
+    }
+
+    /// Checks the block is dated after the recent median time and not too far
+    /// ahead of local time.
+    fn validate_timestamp(
+        &self,
+        previous_headers: &[BlockHeader<Self::Proof>],
+        header: &BlockHeader<Self::Proof>,
+    ) -> Result<(), ValidationError> {
+        let min_timestamp = self.min_timestamp(previous_headers);
+        if header.timestamp < min_timestamp {
+            return Err(ValidationError::TimestampTooOld {
+                timestamp: header.timestamp,
+                min_timestamp,
+            });
+        }
 
+        let max_timestamp = Utc::now().timestamp() + self.rules().max_future_drift_s;
+        if header.timestamp > max_timestamp {
+            return Err(ValidationError::TimestampTooFarAhead {
+                timestamp: header.timestamp,
+                max_timestamp,
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks every engine applies before looking at the proof: the block
+    /// version, linkage to the parent, the stored hash and the timestamp.
+    fn validate_common(
+        &self,
+        previous_headers: &[BlockHeader<Self::Proof>],
+        header: &BlockHeader<Self::Proof>,
+    ) -> Result<(), ValidationError> {
+        validate_version(previous_headers, header)?;
+        validate_link(self.rules().hash, previous_headers, header)?;
+        self.validate_timestamp(previous_headers, header)
+    }
+
+    /// Validates the header of `block` on top of `previous_headers`, then that
+    /// its body matches the header and repeats none of the `on_chain`
+    /// transaction ids.
     fn validate_block(
         &self,
-        previous_block: &Block<Self::Proof>,
+        previous_headers: &[BlockHeader<Self::Proof>],
+        on_chain: &HashSet<String>,
         block: &Block<Self::Proof>,
-    ) -> bool;
+    ) -> Result<(), ValidationError> {
+        self.validate_header(previous_headers, &block.header)?;
+        validate_body(self.rules(), on_chain, block)
+    }
+
+    /// Proof sealing the genesis block. Genesis is never validated against a
+    /// predecessor, so it only has to be deterministic.
+    fn genesis_proof(&self) -> Self::Proof;
+
+    /// Hashes per second measured while producing the last proof, for
+    /// engines that search for one.
+    fn hashrate(&self) -> Option<u64> {
+        None
+    }
+
+    /// Fork choice weight of the chain of `headers`; nodes adopt the valid
+    /// chain with the highest weight. Defaults to the number of blocks.
+    fn chain_weight(&self, headers: &[BlockHeader<Self::Proof>]) -> u128 {
+        headers.len() as u128
+    }
+
+    /// Records a proposal or vote from a peer, for engines that agree on
+    /// blocks by voting. Returns a block once it is final, for the caller to
+    /// append to its chain. `on_chain` holds the ids of the transactions in
+    /// the chain, which proposals may not repeat.
+    fn handle_message(
+        &self,
+        _previous_headers: &[BlockHeader<Self::Proof>],
+        _on_chain: &HashSet<String>,
+        _message: ConsensusMessage,
+    ) -> Result<Option<Block<Self::Proof>>, ValidationError> {
+        Err(ValidationError::UnexpectedMessage)
+    }
+
+    /// Whether the block following `previous_headers` is only final once the
+    /// validators voted on it. Such blocks come from `handle_message`, blocks
+    /// broadcast by peers are refused.
+    fn decides_by_vote(&self, _previous_headers: &[BlockHeader<Self::Proof>]) -> bool {
+        false
+    }
+
+    /// Channel for the consensus messages this engine sends to every
+    /// registered node.
+    fn set_outbox(&mut self, _outbox: UnboundedSender<ConsensusMessage>) {}
+
+    /// Index of the newest block of the chain of `headers` that no fork may
+    /// revert. Only genesis is final unless the engine finalizes blocks.
+    fn finalized_height(&self, _headers: &[BlockHeader<Self::Proof>]) -> u64 {
+        0
+    }
+
+    /// Whether the tip of `headers` may be adopted from a peer. Engines that
+    /// certify a block in the proof of its child cannot tell from the chain
+    /// whether its tip was decided, and refuse it until they saw the
+    /// certificate. Other engines take any valid tip.
+    fn tip_certified(&self, _headers: &[BlockHeader<Self::Proof>]) -> bool {
+        true
+    }
+
+    /// Commit of `tip` with the certificate this node saw for it, for peers
+    /// that missed it, if the engine decides blocks by vote.
+    fn tip_commit(&self, _tip: &Block<Self::Proof>) -> Option<ConsensusMessage> {
+        None
+    }
 
-    fn validate_chain(&self, chain: &Chain<Self>) -> bool {
-        if chain.chain.is_empty() {
-            return true;
+    /// Validates a chain from its headers alone, starting at the genesis block
+    /// hashed `genesis_hash`.
+    fn validate_headers(
+        &self,
+        genesis_hash: &str,
+        headers: &[BlockHeader<Self::Proof>],
+    ) -> Result<(), ValidationError> {
+        let Some(genesis) = headers.first() else {
+            return Ok(());
+        };
+        validate_genesis(self.rules().hash, genesis_hash, genesis)?;
+        for i in 1..headers.len() {
+            self.validate_header(&headers[..i], &headers[i])
+                .map_err(|cause| ValidationError::InvalidBlock {
+                    index: headers[i].index,
+                    cause: Box::new(cause),
+                })?;
         }
-        let genesis = &chain.chain[0];
-        if genesis.index != 0 || genesis.previous_hash != "0" {
-            return false;
+        Ok(())
+    }
+
+    fn validate_chain(&self, chain: &Chain<Self>) -> Result<(), ValidationError> {
+        self.validate_headers(&chain.genesis_hash, &chain.headers())?;
+        let mut on_chain = HashSet::new();
+        for block in &chain.chain {
+            validate_body(self.rules(), &on_chain, block).map_err(|cause| {
+                ValidationError::InvalidBlock {
+                    index: block.header.index,
+                    cause: Box::new(cause),
+                }
+            })?;
+            on_chain.extend(block.body.transaction_ids());
         }
-        for i in 1..chain.chain.len() {
-            if !self.validate_block(&chain.chain[i - 1], &chain.chain[i]) {
-                return false;
-            }
+        Ok(())
+    }
+}
+
+// Checks `genesis` starts the chain and is the block hashed `genesis_hash`
+pub(super) fn validate_genesis<P: Encode>(
+    hash_algorithm: HashAlgorithm,
+    genesis_hash: &str,
+    genesis: &BlockHeader<P>,
+) -> Result<(), ValidationError> {
+    if genesis.index != 0 {
+        return Err(ValidationError::BadIndex {
+            expected: 0,
+            found: genesis.index,
+        });
+    }
+    if genesis.previous_hash != "0" {
+        return Err(ValidationError::BadPreviousHash {
+            expected: "0".to_string(),
+            found: genesis.previous_hash.clone(),
+        });
+    }
+    // A different genesis config, hash algorithm included, yields a
+    // different genesis hash, so foreign networks are caught here
+    if genesis.hash != genesis_hash {
+        return Err(ValidationError::GenesisMismatch {
+            expected: genesis_hash.to_string(),
+            found: genesis.hash.clone(),
+        });
+    }
+    validate_version(&[], genesis)?;
+    validate_hash(hash_algorithm, genesis)
+}
+
+// Index and previous hash of the block that extends `previous_headers`
+pub(super) fn next_position<P>(previous_headers: &[BlockHeader<P>]) -> (u64, &str) {
+    match previous_headers.last() {
+        Some(parent) => (parent.index + 1, &parent.hash),
+        None => (0, "0"),
+    }
+}
+
+// Checks the block follows a version this node has rules for, and none older
+// than its parent's, so the network cannot fall back to retired rules
+fn validate_version<P>(
+    previous_headers: &[BlockHeader<P>],
+    header: &BlockHeader<P>,
+) -> Result<(), ValidationError> {
+    if !SUPPORTED_BLOCK_VERSIONS.contains(&header.version) {
+        return Err(ValidationError::UnsupportedBlockVersion {
+            version: header.version,
+            supported: SUPPORTED_BLOCK_VERSIONS.to_vec(),
+        });
+    }
+    match previous_headers.last() {
+        Some(parent) if header.version < parent.version => {
+            Err(ValidationError::BlockVersionDowngrade {
+                version: header.version,
+                parent_version: parent.version,
+            })
         }
-        true
+        _ => Ok(()),
+    }
+}
+
+// Checks the stored hash matches the header contents
+fn validate_hash<P: Encode>(
+    hash_algorithm: HashAlgorithm,
+    header: &BlockHeader<P>,
+) -> Result<(), ValidationError> {
+    let calculated_hash = crate::utils::hash(
+        hash_algorithm,
+        header.version,
+        header.index,
+        header.timestamp,
+        &header.merkle_root,
+        &header.previous_hash,
+        &header.proof,
+    );
+    if header.hash != calculated_hash {
+        return Err(ValidationError::HashMismatch {
+            calculated: calculated_hash,
+            found: header.hash.clone(),
+        });
+    }
+    Ok(())
+}
+
+// Checks linkage to the parent and that the stored hash matches the contents
+fn validate_link<P: Encode>(
+    hash_algorithm: HashAlgorithm,
+    previous_headers: &[BlockHeader<P>],
+    header: &BlockHeader<P>,
+) -> Result<(), ValidationError> {
+    let Some(parent) = previous_headers.last() else {
+        return Err(ValidationError::MissingParent);
+    };
+
+    if header.index != parent.index + 1 {
+        return Err(ValidationError::BadIndex {
+            expected: parent.index + 1,
+            found: header.index,
+        });
+    }
+
+    if header.previous_hash != parent.hash {
+        return Err(ValidationError::BadPreviousHash {
+            expected: parent.hash.clone(),
+            found: header.previous_hash.clone(),
+        });
+    }
+
+    validate_hash(hash_algorithm, header)
+}
+
+// Checks the body is the one the header commits to and is well formed: only
+// genesis carries genesis data, blocks after it stay within the size limits
+// and every transaction appears once, with an id, a message and a valid
+// signature of its sender
+fn validate_body<P>(
+    rules: &BlockRules,
+    on_chain: &HashSet<String>,
+    block: &Block<P>,
+) -> Result<(), ValidationError> {
+    let calculated_root = block.body.merkle_root(rules.hash);
+    if block.header.merkle_root != calculated_root {
+        return Err(ValidationError::MerkleRootMismatch {
+            calculated: calculated_root,
+            found: block.header.merkle_root.clone(),
+        });
+    }
+
+    if block.header.index != 0 && block.body.genesis.is_some() {
+        return Err(ValidationError::UnexpectedGenesisData {
+            index: block.header.index,
+        });
+    }
+
+    // Genesis is fixed by the network config, whatever its allocations weigh
+    if block.header.index != 0 {
+        let count = block.body.transactions.len();
+        if count > rules.max_block_transactions {
+            return Err(ValidationError::TooManyTransactions {
+                count,
+                max: rules.max_block_transactions,
+            });
+        }
+        let size = block.body.encode().len();
+        if size > rules.max_block_bytes {
+            return Err(ValidationError::BlockTooLarge {
+                size,
+                max: rules.max_block_bytes,
+            });
+        }
+    }
+
+    let mut seen = HashSet::new();
+    for tx in &block.body.transactions {
+        let reason = if let Some(reason) = tx.validity_error() {
+            reason
+        } else if !seen.insert(tx.id.as_str()) {
+            "included more than once"
+        } else if on_chain.contains(&tx.id) {
+            // A signed transaction copied from the chain would replay otherwise
+            "is already in the chain"
+        } else {
+            continue;
+        };
+        return Err(ValidationError::BadTransaction {
+            id: tx.id.clone(),
+            reason: reason.to_string(),
+        });
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PowProof {
+    pub nonce: u64,
+    pub bits: u32,
+}
+
+// Leading byte of each kind of proof in the canonical encoding
+pub(super) const WORK_PROOF_TAG: u8 = 0;
+pub(super) const SIGNED_PROOF_TAG: u8 = 1;
+
+// Consumes the leading byte of a proof, which has to be `tag`
+pub(super) fn expect_proof_tag(input: &mut Decoder<'_>, tag: u8) -> Result<(), DecodeError> {
+    match u8::decode_from(input)? {
+        found if found == tag => Ok(()),
+        found => Err(DecodeError::UnknownTag {
+            kind: "proof",
+            tag: found,
+        }),
     }
 }
 
+impl Encode for PowProof {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        WORK_PROOF_TAG.encode_to(out);
+        self.nonce.encode_to(out);
+        self.bits.encode_to(out);
+    }
+}
+
+impl Decode for PowProof {
+    fn decode_from(input: &mut Decoder<'_>) -> Result<Self, DecodeError> {
+        expect_proof_tag(input, WORK_PROOF_TAG)?;
+        Ok(PowProof {
+            nonce: Decode::decode_from(input)?,
+            bits: Decode::decode_from(input)?,
+        })
+    }
+}
+
+impl PowProof {
+    /// Expected number of hashes it took to find this proof.
+    pub fn work(&self) -> u128 {
+        difficulty::work(self.bits)
+    }
+}
+
+impl fmt::Display for PowProof {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (bits {:08x}, work {})",
+            self.nonce,
+            self.bits,
+            self.work()
+        )
+    }
+}
+
+fn default_mining_threads() -> usize {
+    std::thread::available_parallelism()
+        .map(|threads| threads.get())
+        .unwrap_or(1)
+}
+
+/// Difficulty schedule shared by the proof of work engines.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Retargeting {
+    initial_bits: u32,
+    target_block_time_s: i64,
+    retarget_window: usize,
+}
+
+impl Retargeting {
+    /// `difficulty` is the number of leading zero bits required in the hash
+    /// until the first retarget.
+    pub fn new(difficulty: u32, target_block_time_s: i64, retarget_window: usize) -> Self {
+        Retargeting {
+            initial_bits: difficulty::bits_for_leading_zeros(difficulty),
+            target_block_time_s,
+            retarget_window: retarget_window.max(1),
+        }
+    }
+
+    /// Blocks `next_bits` looks back over: a whole window plus the block
+    /// before it, whose timestamp starts the window.
+    fn history_len(&self) -> usize {
+        self.retarget_window + 1
+    }
+
+    /// Compact target required for the block following `previous_headers`.
+    ///
+    /// The target is retargeted every `retarget_window` blocks, scaled by the
+    /// ratio of the time the last window took to the target block time.
+    pub fn next_bits(&self, previous_headers: &[BlockHeader<PowProof>]) -> u32 {
+        let Some(parent) = previous_headers.last() else {
+            return self.initial_bits;
+        };
+        let current = parent.proof.bits;
+
+        let next_index = parent.index + 1;
+        if next_index % self.retarget_window as u64 != 0
+            || previous_headers.len() <= self.retarget_window
+        {
+            return current;
+        }
+
+        let window_start = &previous_headers[previous_headers.len() - self.retarget_window - 1];
+        let elapsed = parent.timestamp - window_start.timestamp;
+        let expected = self.target_block_time_s * self.retarget_window as i64;
+
+        difficulty::retarget(current, elapsed, expected)
+    }
+
+    fn genesis_proof(&self) -> PowProof {
+        PowProof {
+            nonce: 0,
+            bits: self.initial_bits,
+        }
+    }
+}
+
+impl fmt::Display for Retargeting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Initial target bits = {:08x}, target block time = {}s, retarget every {} blocks",
+            self.initial_bits, self.target_block_time_s, self.retarget_window
+        )
+    }
+}
+
+// Searches the nonce space on `threads` workers, worker `w` trying nonces
+// `w, w + threads, w + 2 * threads, ...` until the `pow_hash` of one of them
+// meets the target or `cancel` is triggered, checked every `check_interval`
+// hashes. Returns the proof, if found, and the total number of hashes computed.
+fn search_nonce<F>(
+    bits: u32,
+    threads: usize,
+    check_interval: u64,
+    cancel: &CancellationToken,
+    pow_hash: &F,
+) -> (Option<PowProof>, u64)
+where
+    F: Fn(&PowProof) -> [u8; 32] + Sync,
+{
+    let found = AtomicBool::new(false);
+    let hashes = AtomicU64::new(0);
+    let result = Mutex::new(None);
+
+    std::thread::scope(|scope| {
+        for worker in 0..threads {
+            let (found, hashes, result) = (&found, &hashes, &result);
+            scope.spawn(move || {
+                let mut proof = PowProof {
+                    nonce: worker as u64,
+                    bits,
+                };
+                let mut attempts = 0u64;
+
+                while !found.load(Ordering::Relaxed) {
+                    let hash = pow_hash(&proof);
+                    attempts += 1;
+                    if attempts.is_multiple_of(check_interval) && cancel.is_cancelled() {
+                        found.store(true, Ordering::Relaxed);
+                        break;
+                    }
+                    if difficulty::meets_target(&hash, bits) {
+                        if !found.swap(true, Ordering::Relaxed) {
+                            *result.lock().unwrap() = Some(proof);
+                        }
+                        break;
+                    }
+                    proof.nonce = proof.nonce.wrapping_add(threads as u64);
+                }
+                hashes.fetch_add(attempts, Ordering::Relaxed);
+            });
+        }
+    });
+
+    (result.into_inner().unwrap(), hashes.into_inner())
+}
+
+// Runs the nonce search on blocking threads and records the hashrate
+async fn mine_nonce<F>(
+    next_index: u64,
+    bits: u32,
+    threads: usize,
+    check_interval: u64,
+    last_hashrate: Arc<AtomicU64>,
+    cancel: CancellationToken,
+    pow_hash: F,
+) -> Option<PowProof>
+where
+    F: Fn(&PowProof) -> [u8; 32] + Send + Sync + 'static,
+{
+    let started = Instant::now();
+    let (proof, hashes) = tokio::task::spawn_blocking(move || {
+        search_nonce(bits, threads, check_interval, &cancel, &pow_hash)
+    })
+    .await
+    .expect("Mining task failed");
+
+    let hashrate = (hashes as f64 / started.elapsed().as_secs_f64().max(1e-3)) as u64;
+    last_hashrate.store(hashrate, Ordering::Relaxed);
+    match proof {
+        Some(_) => println!(
+            "Found proof for block #{} after {} hashes on {} workers ({} H/s)",
+            next_index, hashes, threads, hashrate
+        ),
+        None => println!(
+            "Cancelled proof search for block #{} after {} hashes",
+            next_index, hashes
+        ),
+    }
+    proof
+}
+
+// Checks the block uses the scheduled difficulty and its proof of work hash,
+// if it could be computed, meets it
+fn validate_work(
+    expected_bits: u32,
+    header: &BlockHeader<PowProof>,
+    pow_hash: Option<[u8; 32]>,
+) -> Result<(), ValidationError> {
+    if header.proof.bits != expected_bits {
+        return Err(ValidationError::WrongDifficulty {
+            expected: expected_bits,
+            found: header.proof.bits,
+        });
+    }
+
+    match pow_hash {
+        Some(hash) if difficulty::meets_target(&hash, header.proof.bits) => Ok(()),
+        _ => Err(ValidationError::InsufficientWork {
+            hash: header.hash.clone(),
+            bits: header.proof.bits,
+        }),
+    }
+}
+
+// Total work, so a shorter chain of harder blocks beats many easy ones
+fn total_work(headers: &[BlockHeader<PowProof>]) -> u128 {
+    headers.iter().fold(0u128, |total, header| {
+        total.saturating_add(header.proof.work())
+    })
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProofOfWork {
-    difficulty: usize,
+    #[serde(default)]
+    pub(super) rules: BlockRules,
+    #[serde(flatten)]
+    retargeting: Retargeting,
+    #[serde(skip, default = "default_mining_threads")]
+    mining_threads: usize,
+    // Shared between clones so the miner's measurements are visible to the API
+    #[serde(skip)]
+    last_hashrate: Arc<AtomicU64>,
 }
 
+// How many block hashes a worker computes between checks for cancellation
+const CANCEL_CHECK_INTERVAL: u64 = 1024;
+
 impl ProofOfWork {
-    pub fn new(difficulty: usize) -> Self {
-        ProofOfWork { difficulty }
+    pub fn new(rules: BlockRules, retargeting: Retargeting) -> Self {
+        ProofOfWork {
+            rules,
+            retargeting,
+            mining_threads: default_mining_threads(),
+            last_hashrate: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn set_mining_threads(&mut self, threads: usize) {
+        self.mining_threads = threads.max(1);
     }
 }
 
 impl fmt::Display for ProofOfWork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.retargeting)
+    }
+}
+
+impl Consensus for ProofOfWork {
+    type Proof = PowProof;
+
+    fn prove<'a>(
+        &'a self,
+        previous_headers: &'a [BlockHeader<Self::Proof>],
+        timestamp: i64,
+        body: &'a BlockBody,
+        cancel: CancellationToken,
+    ) -> Pin<Box<dyn Future<Output = Option<Self::Proof>> + Send + 'a>> {
+        let bits = self.retargeting.next_bits(previous_headers);
+        let (next_index, previous_hash) = next_position(previous_headers);
+        let hash_algorithm = self.rules.hash;
+        let merkle_root = body.merkle_root(hash_algorithm);
+        let previous_hash = previous_hash.to_string();
+
+        Box::pin(mine_nonce(
+            next_index,
+            bits,
+            self.mining_threads,
+            CANCEL_CHECK_INTERVAL,
+            self.last_hashrate.clone(),
+            cancel,
+            move |proof| {
+                crate::utils::hash_bytes(
+                    hash_algorithm,
+                    BLOCK_VERSION,
+                    next_index,
+                    timestamp,
+                    &merkle_root,
+                    &previous_hash,
+                    proof,
+                )
+            },
+        ))
+    }
+
+    fn rules(&self) -> &BlockRules {
+        &self.rules
+    }
+
+    fn set_rules(&mut self, rules: BlockRules) {
+        self.rules = rules;
+    }
+
+    fn genesis_proof(&self) -> Self::Proof {
+        self.retargeting.genesis_proof()
+    }
+
+    fn history_len(&self) -> usize {
+        RECENT_BLOCKS_WINDOW
+            .max(self.rules.median_time_span)
+            .max(self.retargeting.history_len())
+    }
+
+    fn validate_header(
+        &self,
+        previous_headers: &[BlockHeader<Self::Proof>],
+        header: &BlockHeader<Self::Proof>,
+    ) -> Result<(), ValidationError> {
+        self.validate_common(previous_headers, header)?;
+
+        let hash = <[u8; 32]>::try_from(hex::decode(&header.hash).unwrap_or_default()).ok();
+        validate_work(self.retargeting.next_bits(previous_headers), header, hash)
+    }
+
+    fn hashrate(&self) -> Option<u64> {
+        Some(self.last_hashrate.load(Ordering::Relaxed))
+    }
+
+    fn chain_weight(&self, headers: &[BlockHeader<Self::Proof>]) -> u128 {
+        total_work(headers)
+    }
+}
+
+/// Proof of work over scrypt, a memory-hard function, so mining is bound by
+/// memory rather than raw hashing speed. The block hash is fed through scrypt
+/// and the result has to meet the difficulty target.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemoryHardProofOfWork {
+    #[serde(default)]
+    pub(super) rules: BlockRules,
+    #[serde(flatten)]
+    retargeting: Retargeting,
+    /// Memory each scrypt evaluation needs, in KiB, a power of two
+    memory_cost_kib: u32,
+    #[serde(skip, default = "default_mining_threads")]
+    mining_threads: usize,
+    #[serde(skip)]
+    last_hashrate: Arc<AtomicU64>,
+}
+
+// scrypt block size, with it every unit of the cost parameter takes 1 KiB
+const SCRYPT_BLOCK_SIZE: u32 = 8;
+// Domain separation for the memory-hard proof of work
+const SCRYPT_SALT: &[u8] = b"rustchain memory hard proof of work";
+// A scrypt evaluation takes milliseconds, so check for cancellation often
+const MEMORY_HARD_CANCEL_CHECK_INTERVAL: u64 = 4;
+
+impl MemoryHardProofOfWork {
+    /// `memory_cost_kib` is rounded down to a power of two, at least 2 KiB.
+    pub fn new(rules: BlockRules, retargeting: Retargeting, memory_cost_kib: u32) -> Self {
+        MemoryHardProofOfWork {
+            rules,
+            retargeting,
+            memory_cost_kib: 1 << memory_cost_kib.max(2).ilog2(),
+            mining_threads: default_mining_threads(),
+            last_hashrate: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn set_mining_threads(&mut self, threads: usize) {
+        self.mining_threads = threads.max(1);
+    }
+
+    fn scrypt_params(&self) -> scrypt::Params {
+        let log_n = self.memory_cost_kib.max(2).ilog2() as u8;
+        scrypt::Params::new(log_n, SCRYPT_BLOCK_SIZE, 1, 32)
+            .unwrap_or_else(|e| panic!("Invalid memory cost {}: {}", self.memory_cost_kib, e))
+    }
+}
+
+fn scrypt_hash(block_hash: &[u8; 32], params: &scrypt::Params) -> [u8; 32] {
+    let mut output = [0u8; 32];
+    scrypt::scrypt(block_hash, SCRYPT_SALT, params, &mut output)
+        .expect("Output length matches the scrypt params");
+    output
+}
+
+impl fmt::Display for MemoryHardProofOfWork {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "Difficulty (leading zeros in hash) = {}",
-            self.difficulty
+            "{}, memory cost = {} KiB",
+            self.retargeting, self.memory_cost_kib
         )
     }
 }
 
-impl Consensus for ProofOfWork {
-    type Proof = u64;
+impl Consensus for MemoryHardProofOfWork {
+    type Proof = PowProof;
+
+    fn prove<'a>(
+        &'a self,
+        previous_headers: &'a [BlockHeader<Self::Proof>],
+        timestamp: i64,
+        body: &'a BlockBody,
+        cancel: CancellationToken,
+    ) -> Pin<Box<dyn Future<Output = Option<Self::Proof>> + Send + 'a>> {
+        let bits = self.retargeting.next_bits(previous_headers);
+        let (next_index, previous_hash) = next_position(previous_headers);
+        let hash_algorithm = self.rules.hash;
+        let merkle_root = body.merkle_root(hash_algorithm);
+        let previous_hash = previous_hash.to_string();
+        let params = self.scrypt_params();
+
+        Box::pin(mine_nonce(
+            next_index,
+            bits,
+            self.mining_threads,
+            MEMORY_HARD_CANCEL_CHECK_INTERVAL,
+            self.last_hashrate.clone(),
+            cancel,
+            move |proof| {
+                let block_hash = crate::utils::hash_bytes(
+                    hash_algorithm,
+                    BLOCK_VERSION,
+                    next_index,
+                    timestamp,
+                    &merkle_root,
+                    &previous_hash,
+                    proof,
+                );
+                scrypt_hash(&block_hash, &params)
+            },
+        ))
+    }
+
+    fn rules(&self) -> &BlockRules {
+        &self.rules
+    }
+
+    fn set_rules(&mut self, rules: BlockRules) {
+        self.rules = rules;
+    }
+
+    fn genesis_proof(&self) -> Self::Proof {
+        self.retargeting.genesis_proof()
+    }
+
+    fn history_len(&self) -> usize {
+        RECENT_BLOCKS_WINDOW
+            .max(self.rules.median_time_span)
+            .max(self.retargeting.history_len())
+    }
+
+    fn validate_header(
+        &self,
+        previous_headers: &[BlockHeader<Self::Proof>],
+        header: &BlockHeader<Self::Proof>,
+    ) -> Result<(), ValidationError> {
+        self.validate_common(previous_headers, header)?;
+
+        let hash = <[u8; 32]>::try_from(hex::decode(&header.hash).unwrap_or_default())
+            .ok()
+            .map(|block_hash| scrypt_hash(&block_hash, &self.scrypt_params()));
+        validate_work(self.retargeting.next_bits(previous_headers), header, hash)
+    }
+
+    fn hashrate(&self) -> Option<u64> {
+        Some(self.last_hashrate.load(Ordering::Relaxed))
+    }
+
+    fn chain_weight(&self, headers: &[BlockHeader<Self::Proof>]) -> u128 {
+        total_work(headers)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedProof {
+    pub validator: String,
+    pub signature: String,
+}
+
+impl SignedProof {
+    // Genesis is never validated against a predecessor, so it carries no signature
+    fn unsigned() -> Self {
+        SignedProof {
+            validator: String::new(),
+            signature: String::new(),
+        }
+    }
+}
+
+impl Encode for SignedProof {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        SIGNED_PROOF_TAG.encode_to(out);
+        self.validator.encode_to(out);
+        self.signature.encode_to(out);
+    }
+}
+
+impl Decode for SignedProof {
+    fn decode_from(input: &mut Decoder<'_>) -> Result<Self, DecodeError> {
+        expect_proof_tag(input, SIGNED_PROOF_TAG)?;
+        Ok(SignedProof {
+            validator: Decode::decode_from(input)?,
+            signature: Decode::decode_from(input)?,
+        })
+    }
+}
+
+impl fmt::Display for SignedProof {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.validator.is_empty() {
+            write!(f, "unsigned")
+        } else {
+            write!(f, "signed by {}", self.validator)
+        }
+    }
+}
+
+// Hash of the block contents the validator signs, excluding the signature itself
+pub(super) fn seal_hash(
+    hash_algorithm: HashAlgorithm,
+    version: u32,
+    index: u64,
+    timestamp: i64,
+    merkle_root: &str,
+    previous_hash: &str,
+    validator: &str,
+) -> String {
+    crate::utils::hash(
+        hash_algorithm,
+        version,
+        index,
+        timestamp,
+        merkle_root,
+        previous_hash,
+        &validator,
+    )
+}
+
+// Signs the next block if `key` belongs to the validator elected for it
+fn seal(
+    hash_algorithm: HashAlgorithm,
+    key: &SigningKey,
+    elected: &str,
+    next_index: u64,
+    timestamp: i64,
+    merkle_root: &str,
+    previous_hash: &str,
+) -> Option<SignedProof> {
+    let validator = keys::public_key_hex(key);
+    if elected != validator {
+        return None;
+    }
+
+    let seal = seal_hash(
+        hash_algorithm,
+        BLOCK_VERSION,
+        next_index,
+        timestamp,
+        merkle_root,
+        previous_hash,
+        &validator,
+    );
+    let signature = keys::sign(key, seal.as_bytes());
+    Some(SignedProof {
+        validator,
+        signature,
+    })
+}
+
+// Checks the block is signed by the validator elected for it
+fn verify_seal(
+    hash_algorithm: HashAlgorithm,
+    header: &BlockHeader<SignedProof>,
+    elected: Option<&str>,
+) -> Result<(), ValidationError> {
+    if elected != Some(header.proof.validator.as_str()) {
+        return Err(ValidationError::WrongValidator {
+            expected: elected.map(str::to_string),
+            found: header.proof.validator.clone(),
+        });
+    }
+
+    let seal = seal_hash(
+        hash_algorithm,
+        header.version,
+        header.index,
+        header.timestamp,
+        &header.merkle_root,
+        &header.previous_hash,
+        &header.proof.validator,
+    );
+    if !keys::verify(
+        &header.proof.validator,
+        seal.as_bytes(),
+        &header.proof.signature,
+    ) {
+        return Err(ValidationError::BadSignature {
+            validator: header.proof.validator.clone(),
+        });
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProofOfStake {
+    #[serde(default)]
+    pub(super) rules: BlockRules,
+    stakes: BTreeMap<String, u64>,
+    #[serde(skip)]
+    signing_key: Option<SigningKey>,
+}
+
+impl ProofOfStake {
+    pub fn new(rules: BlockRules, stakes: BTreeMap<String, u64>) -> Self {
+        ProofOfStake {
+            rules,
+            stakes,
+            signing_key: None,
+        }
+    }
+
+    pub fn set_signing_key(&mut self, key: SigningKey) {
+        self.signing_key = Some(key);
+    }
+
+    pub fn has_stakes(&self) -> bool {
+        self.stakes.values().any(|stake| *stake > 0)
+    }
+
+    /// Picks the validator for `index` with probability proportional to stake.
+    /// Seeded from the parent hash so every node elects the same validator.
+    pub fn elect_validator(&self, index: u64, previous_hash: &str) -> Option<&str> {
+        let total_stake: u64 = self.stakes.values().sum();
+        if total_stake == 0 {
+            return None;
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update(previous_hash.as_bytes());
+        hasher.update(index.to_be_bytes());
+        let seed = hasher.finalize();
+        let mut seed_bytes = [0u8; 8];
+        seed_bytes.copy_from_slice(&seed[..8]);
+        let mut ticket = u64::from_be_bytes(seed_bytes) % total_stake;
+
+        for (validator, stake) in &self.stakes {
+            if ticket < *stake {
+                return Some(validator);
+            }
+            ticket -= stake;
+        }
+        None
+    }
+}
+
+impl fmt::Display for ProofOfStake {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Validators = {}, total stake = {}",
+            self.stakes.len(),
+            self.stakes.values().sum::<u64>()
+        )
+    }
+}
+
+impl Consensus for ProofOfStake {
+    type Proof = SignedProof;
 
     fn prove<'a>(
         &'a self,
-        next_index: u64,
+        previous_headers: &'a [BlockHeader<Self::Proof>],
         timestamp: i64,
-        data: &'a str,
-        previous_hash: &'a str,
-    ) -> Pin<Box<dyn Future<Output = Self::Proof> + Send + 'a>> {
-        let difficulty = self.difficulty;
-        let data_clone = data.to_string();
-        let previous_hash_clone = previous_hash.to_string();
+        body: &'a BlockBody,
+        _cancel: CancellationToken,
+    ) -> Pin<Box<dyn Future<Output = Option<Self::Proof>> + Send + 'a>> {
+        let (next_index, previous_hash) = next_position(previous_headers);
 
         Box::pin(async move {
-            tokio::task::spawn_blocking(move || {
-                let target = "0".repeat(difficulty);
-                let mut proof = 0u64;
-
-                loop {
-                    let hash = crate::utils::hash(
-                        next_index,
-                        timestamp,
-                        &data_clone,
-                        &previous_hash_clone,
-                        &proof,
-                    );
-                    if hash.starts_with(&target) {
-                        return proof;
-                    }
-                    proof += 1;
-                }
-            })
-            .await
-            .expect("Mining task failed")
+            let key = self.signing_key.as_ref()?;
+            let elected = self.elect_validator(next_index, previous_hash)?;
+            seal(
+                self.rules.hash,
+                key,
+                elected,
+                next_index,
+                timestamp,
+                &body.merkle_root(self.rules.hash),
+                previous_hash,
+            )
         })
     }
 
-    fn validate_block(
+    fn rules(&self) -> &BlockRules {
+        &self.rules
+    }
+
+    fn set_rules(&mut self, rules: BlockRules) {
+        self.rules = rules;
+    }
+
+    fn genesis_proof(&self) -> Self::Proof {
+        SignedProof::unsigned()
+    }
+
+    fn validate_header(
         &self,
-        previous_block: &Block<Self::Proof>,
-        block: &Block<Self::Proof>,
-    ) -> bool {
-        if block.index != previous_block.index + 1 {
-            return false;
+        previous_headers: &[BlockHeader<Self::Proof>],
+        header: &BlockHeader<Self::Proof>,
+    ) -> Result<(), ValidationError> {
+        self.validate_common(previous_headers, header)?;
+        verify_seal(
+            self.rules.hash,
+            header,
+            self.elect_validator(header.index, &header.previous_hash),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProofOfAuthority {
+    #[serde(default)]
+    pub(super) rules: BlockRules,
+    authorities: Vec<String>,
+    #[serde(skip)]
+    signing_key: Option<SigningKey>,
+}
+
+impl ProofOfAuthority {
+    pub fn new(rules: BlockRules, authorities: Vec<String>) -> Self {
+        ProofOfAuthority {
+            rules,
+            authorities,
+            signing_key: None,
+        }
+    }
+
+    pub fn set_signing_key(&mut self, key: SigningKey) {
+        self.signing_key = Some(key);
+    }
+
+    /// Authority whose turn it is to produce the block at `index`.
+    pub fn authority_for(&self, index: u64) -> Option<&str> {
+        if self.authorities.is_empty() {
+            return None;
         }
+        let slot = (index % self.authorities.len() as u64) as usize;
+        Some(&self.authorities[slot])
+    }
+}
+
+impl fmt::Display for ProofOfAuthority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Authorities = {}", self.authorities.len())
+    }
+}
+
+impl Consensus for ProofOfAuthority {
+    type Proof = SignedProof;
+
+    fn prove<'a>(
+        &'a self,
+        previous_headers: &'a [BlockHeader<Self::Proof>],
+        timestamp: i64,
+        body: &'a BlockBody,
+        _cancel: CancellationToken,
+    ) -> Pin<Box<dyn Future<Output = Option<Self::Proof>> + Send + 'a>> {
+        let (next_index, previous_hash) = next_position(previous_headers);
+
+        Box::pin(async move {
+            let key = self.signing_key.as_ref()?;
+            let authority = self.authority_for(next_index)?;
+            seal(
+                self.rules.hash,
+                key,
+                authority,
+                next_index,
+                timestamp,
+                &body.merkle_root(self.rules.hash),
+                previous_hash,
+            )
+        })
+    }
+
+    fn rules(&self) -> &BlockRules {
+        &self.rules
+    }
+
+    fn set_rules(&mut self, rules: BlockRules) {
+        self.rules = rules;
+    }
+
+    fn genesis_proof(&self) -> Self::Proof {
+        SignedProof::unsigned()
+    }
+
+    fn validate_header(
+        &self,
+        previous_headers: &[BlockHeader<Self::Proof>],
+        header: &BlockHeader<Self::Proof>,
+    ) -> Result<(), ValidationError> {
+        self.validate_common(previous_headers, header)?;
+        verify_seal(self.rules.hash, header, self.authority_for(header.index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{GenesisConfig, MessageTransaction};
+
+    const TIMESTAMP: i64 = crate::blockchain::genesis::GENESIS_TIMESTAMP + 60;
+
+    fn genesis<C: Consensus>(consensus: &C) -> BlockHeader<C::Proof> {
+        GenesisConfig::default().spec(consensus).block().header
+    }
+
+    // Child of `parent` over an empty body, sealed by `key` whether or not it
+    // was elected
+    fn signed_header(
+        key: &SigningKey,
+        parent: &BlockHeader<SignedProof>,
+        timestamp: i64,
+    ) -> BlockHeader<SignedProof> {
+        let hash = HashAlgorithm::Sha256;
+        let merkle_root = BlockBody::default().merkle_root(hash);
+        let index = parent.index + 1;
+        let validator = keys::public_key_hex(key);
+        let proof = seal(
+            hash,
+            key,
+            &validator,
+            index,
+            timestamp,
+            &merkle_root,
+            &parent.hash,
+        )
+        .unwrap();
+        BlockHeader::new(
+            index,
+            timestamp,
+            merkle_root,
+            parent.hash.clone(),
+            proof,
+            hash,
+        )
+    }
+
+    fn stake_keys() -> (ProofOfStake, Vec<SigningKey>) {
+        let keys: Vec<SigningKey> = [0x11, 0x22, 0x33]
+            .iter()
+            .map(|byte| SigningKey::from_bytes(&[*byte; 32]))
+            .collect();
+        let stakes = keys
+            .iter()
+            .zip([1, 2, 3])
+            .map(|(key, stake)| (keys::public_key_hex(key), stake))
+            .collect();
+        (ProofOfStake::new(BlockRules::default(), stakes), keys)
+    }
 
-        if block.previous_hash != previous_block.hash {
-            return false;
+    #[test]
+    fn elect_validator_is_deterministic_and_stake_weighted() {
+        let (consensus, keys) = stake_keys();
+        let again = ProofOfStake::new(BlockRules::default(), consensus.stakes.clone());
+        let mut elected = BTreeMap::new();
+        for index in 1..200 {
+            let previous_hash = format!("{:064x}", index * 7919);
+            let validator = consensus.elect_validator(index, &previous_hash).unwrap();
+            assert_eq!(
+                Some(validator),
+                again.elect_validator(index, &previous_hash)
+            );
+            *elected.entry(validator.to_string()).or_insert(0) += 1;
         }
+        // Every staker gets a turn
+        assert_eq!(elected.len(), keys.len());
 
-        let calculated_hash = crate::utils::hash(
-            block.index,
-            block.timestamp,
-            &block.data,
-            &block.previous_hash,
-            &block.proof,
+        let mut stakes = consensus.stakes.clone();
+        stakes.insert(keys::public_key_hex(&keys[0]), 0);
+        stakes.insert(keys::public_key_hex(&keys[1]), 0);
+        let single = ProofOfStake::new(BlockRules::default(), stakes);
+        let only = keys::public_key_hex(&keys[2]);
+        for index in 1..50 {
+            assert_eq!(single.elect_validator(index, "0"), Some(only.as_str()));
+        }
+        assert_eq!(
+            ProofOfStake::new(BlockRules::default(), BTreeMap::new()).elect_validator(1, "0"),
+            None
         );
-        if block.hash != calculated_hash {
-            return false;
+    }
+
+    #[test]
+    fn stake_rejects_block_from_wrong_validator() {
+        let (consensus, keys) = stake_keys();
+        let genesis = genesis(&consensus);
+        let elected = consensus.elect_validator(1, &genesis.hash).unwrap();
+        let elected_key = keys
+            .iter()
+            .find(|key| keys::public_key_hex(key) == elected)
+            .unwrap();
+        let other_key = keys
+            .iter()
+            .find(|key| keys::public_key_hex(key) != elected)
+            .unwrap();
+
+        let header = signed_header(elected_key, &genesis, TIMESTAMP);
+        assert!(consensus
+            .validate_header(std::slice::from_ref(&genesis), &header)
+            .is_ok());
+
+        let header = signed_header(other_key, &genesis, TIMESTAMP);
+        assert!(matches!(
+            consensus.validate_header(std::slice::from_ref(&genesis), &header),
+            Err(ValidationError::WrongValidator { expected: Some(expected), found })
+                if expected == elected && found == keys::public_key_hex(other_key)
+        ));
+    }
+
+    // Chain of headers dated `timestamps`, on top of genesis
+    fn dated_headers(key: &SigningKey, timestamps: &[i64]) -> Vec<BlockHeader<SignedProof>> {
+        let consensus = ProofOfAuthority::new(BlockRules::default(), Vec::new());
+        let mut headers = vec![genesis(&consensus)];
+        for timestamp in timestamps {
+            let header = signed_header(key, headers.last().unwrap(), *timestamp);
+            headers.push(header);
+        }
+        headers.remove(0);
+        headers
+    }
+
+    #[test]
+    fn timestamp_must_pass_median_of_recent_blocks() {
+        let key = SigningKey::from_bytes(&[0x11; 32]);
+        let rules = BlockRules {
+            median_time_span: 3,
+            ..BlockRules::default()
+        };
+        let consensus = ProofOfAuthority::new(rules, vec![keys::public_key_hex(&key)]);
+        assert_eq!(consensus.min_timestamp(&[]), i64::MIN);
+
+        // Only the last three count, their median is 200
+        let headers = dated_headers(&key, &[900, 100, 300, 200]);
+        assert_eq!(consensus.min_timestamp(&headers), 201);
+
+        let parent = headers.last().unwrap();
+        let header = signed_header(&key, parent, 201);
+        assert!(consensus.validate_timestamp(&headers, &header).is_ok());
+        let header = signed_header(&key, parent, 200);
+        assert!(matches!(
+            consensus.validate_timestamp(&headers, &header),
+            Err(ValidationError::TimestampTooOld {
+                timestamp: 200,
+                min_timestamp: 201
+            })
+        ));
+    }
+
+    #[test]
+    fn timestamp_may_not_drift_too_far_ahead() {
+        let key = SigningKey::from_bytes(&[0x11; 32]);
+        let consensus = ProofOfAuthority::new(BlockRules::default(), Vec::new());
+        let headers = dated_headers(&key, &[TIMESTAMP]);
+        let parent = headers.last().unwrap();
+        let drift = consensus.rules().max_future_drift_s;
+
+        // Local time only moves forward while validating, so the latest
+        // allowed timestamp taken now stays allowed
+        let latest = Utc::now().timestamp() + drift;
+        let header = signed_header(&key, parent, latest);
+        assert!(consensus.validate_timestamp(&headers, &header).is_ok());
+
+        let header = signed_header(&key, parent, latest + drift);
+        assert!(matches!(
+            consensus.validate_timestamp(&headers, &header),
+            Err(ValidationError::TimestampTooFarAhead { timestamp, .. }) if timestamp == latest + drift
+        ));
+    }
+
+    // Plain hash of the nonce, out of reach of a 250 leading zero bits target
+    fn slow_hash(proof: &PowProof) -> [u8; 32] {
+        Sha256::digest(proof.nonce.to_be_bytes()).into()
+    }
+
+    #[test]
+    fn cancelled_nonce_search_returns_no_proof() {
+        let bits = difficulty::bits_for_leading_zeros(250);
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let (proof, hashes) = search_nonce(bits, 2, 16, &cancel, &slow_hash);
+        assert!(proof.is_none());
+        // No worker hashes past its first cancellation check
+        assert!(hashes <= 2 * 16);
+    }
+
+    #[tokio::test]
+    async fn cancelling_mining_stops_it() {
+        let bits = difficulty::bits_for_leading_zeros(250);
+        let cancel = CancellationToken::new();
+        let hashrate = Arc::new(AtomicU64::new(0));
+        let mining = tokio::spawn(mine_nonce(
+            1,
+            bits,
+            2,
+            CANCEL_CHECK_INTERVAL,
+            hashrate.clone(),
+            cancel.clone(),
+            slow_hash,
+        ));
+
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        cancel.cancel();
+        let proof = tokio::time::timeout(std::time::Duration::from_secs(5), mining)
+            .await
+            .expect("Mining kept running after cancellation")
+            .unwrap();
+        assert!(proof.is_none());
+        assert!(hashrate.load(Ordering::Relaxed) > 0);
+    }
+
+    // Block at height 1 carrying `transactions`, its proof left unchecked
+    fn block_with(transactions: Vec<MessageTransaction>) -> Block<PowProof> {
+        Block::new(
+            1,
+            BlockBody::new(transactions),
+            TIMESTAMP,
+            PowProof { nonce: 0, bits: 0 },
+            "0".to_string(),
+            HashAlgorithm::Sha256,
+        )
+    }
+
+    fn body_error(transaction: MessageTransaction) -> String {
+        let block = block_with(vec![transaction]);
+        match validate_body(&BlockRules::default(), &HashSet::new(), &block) {
+            Err(ValidationError::BadTransaction { reason, .. }) => reason,
+            other => panic!("Expected a bad transaction, got {:?}", other),
         }
+    }
+
+    #[test]
+    fn body_rejects_unsigned_forged_and_misnamed_transactions() {
+        let key = SigningKey::from_bytes(&[0x11; 32]);
+        let signed = MessageTransaction::signed(&key, "hello".to_string());
+        let block = block_with(vec![signed.clone()]);
+        assert!(validate_body(&BlockRules::default(), &HashSet::new(), &block).is_ok());
 
-        let target = "0".repeat(self.difficulty);
-        block.hash.starts_with(&target)
+        let mut unsigned = signed.clone();
+        unsigned.sender = String::new();
+        unsigned.signature = String::new();
+        unsigned.id = unsigned.derived_id();
+        assert_eq!(body_error(unsigned), "is not signed");
+
+        // Signed by another key than the sender's
+        let mut forged = signed.clone();
+        forged.signature =
+            MessageTransaction::signed(&SigningKey::from_bytes(&[0x22; 32]), "hello".to_string())
+                .signature;
+        assert_eq!(body_error(forged), "has an invalid signature");
+
+        let mut misnamed = signed;
+        misnamed.id = "5f0c3d9e-1b2a-4c6d-8e7f-000000000001".to_string();
+        assert_eq!(
+            body_error(misnamed),
+            "has an id other than the hash of its signed content"
+        );
     }
 }
@@ -0,0 +1,163 @@
+//! 256-bit proof of work targets in Bitcoin's compact "bits" encoding.
+//!
+//! A compact value stores a target as a one byte exponent (the length of the
+//! target in bytes) followed by a three byte mantissa. A block hash, read as a
+//! big-endian 256-bit number, meets the target if it is less than or equal to it.
+
+use ethnum::U256;
+
+// Easiest target the network accepts, roughly one leading zero bit
+pub const MAX_TARGET_BITS: u32 = 0x207fffff;
+
+pub fn target_from_bits(bits: u32) -> U256 {
+    let exponent = bits >> 24;
+    let mantissa = U256::from(bits & 0x007fffff);
+
+    if exponent <= 3 {
+        mantissa >> (8 * (3 - exponent))
+    } else {
+        let shift = 8 * (exponent - 3);
+        if shift >= 256 || mantissa.leading_zeros() < shift {
+            // Does not fit in 256 bits, nothing can meet it
+            return U256::ZERO;
+        }
+        mantissa << shift
+    }
+}
+
+pub fn bits_from_target(target: U256) -> u32 {
+    let mut size = (256 - target.leading_zeros()).div_ceil(8);
+    let mut compact = if size <= 3 {
+        target.as_u32() << (8 * (3 - size))
+    } else {
+        (target >> (8 * (size - 3))).as_u32()
+    };
+
+    // The mantissa's top bit is a sign bit, move it into the exponent instead
+    if compact & 0x00800000 != 0 {
+        compact >>= 8;
+        size += 1;
+    }
+    compact | (size << 24)
+}
+
+/// Compact target requiring roughly `zero_bits` leading zero bits in the hash.
+pub fn bits_for_leading_zeros(zero_bits: u32) -> u32 {
+    let target = U256::MAX >> zero_bits.min(255);
+    bits_from_target(target).min(MAX_TARGET_BITS)
+}
+
+pub fn meets_target(hash: &[u8; 32], bits: u32) -> bool {
+    U256::from_be_bytes(*hash) <= target_from_bits(bits)
+}
+
+/// Expected number of hashes needed to meet the target, `2^256 / (target + 1)`.
+pub fn work(bits: u32) -> u128 {
+    let target = target_from_bits(bits);
+    let work = match target.checked_add(U256::ONE) {
+        // 2^256 does not fit, so compute (2^256 - target - 1) / (target + 1) + 1,
+        // which only a zero target, met by no hash, pushes past 256 bits
+        Some(divisor) => (!target / divisor).saturating_add(U256::ONE),
+        None => U256::ONE,
+    };
+    if work > U256::from(u128::MAX) {
+        u128::MAX
+    } else {
+        work.as_u128()
+    }
+}
+
+/// Scales the target by how long the last window actually took compared to
+/// the expected timespan. Adjustments are clamped to a factor of four.
+pub fn retarget(bits: u32, actual_timespan: i64, expected_timespan: i64) -> u32 {
+    let expected = expected_timespan.max(1);
+    let actual = actual_timespan.clamp(expected / 4, expected * 4).max(1);
+
+    let max_target = target_from_bits(MAX_TARGET_BITS);
+    let scaled = (target_from_bits(bits) / U256::from(expected as u64))
+        .checked_mul(U256::from(actual as u64))
+        .unwrap_or(max_target);
+
+    bits_from_target(scaled.clamp(U256::ONE, max_target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_from_bits_places_the_mantissa() {
+        // Bitcoin's genesis difficulty
+        assert_eq!(
+            target_from_bits(0x1d00ffff),
+            U256::from(0xffffu32) << (8 * (0x1d - 3))
+        );
+        assert_eq!(target_from_bits(0x03123456), U256::from(0x123456u32));
+        assert_eq!(target_from_bits(0x02123456), U256::from(0x1234u32));
+        assert_eq!(target_from_bits(0x01123456), U256::from(0x12u32));
+        // The sign bit is not part of the mantissa
+        assert_eq!(target_from_bits(0x04923456), U256::from(0x12345600u32));
+    }
+
+    #[test]
+    fn target_from_bits_overflow_is_unreachable() {
+        assert_eq!(target_from_bits(0x22010000), U256::ZERO);
+        assert_eq!(target_from_bits(0xff123456), U256::ZERO);
+        assert_eq!(target_from_bits(0x21008000), U256::ONE << 255);
+    }
+
+    #[test]
+    fn bits_from_target_round_trips() {
+        for bits in [
+            0x1d00ffff,
+            0x1f00ffff,
+            MAX_TARGET_BITS,
+            0x03123456,
+            0x1b0404cb,
+        ] {
+            assert_eq!(bits_from_target(target_from_bits(bits)), bits);
+        }
+    }
+
+    #[test]
+    fn bits_from_target_keeps_the_sign_bit_clear() {
+        // 0x80 would set the sign bit as the top mantissa byte
+        assert_eq!(bits_from_target(U256::from(0x80u32)), 0x02008000);
+        assert_eq!(bits_from_target(U256::from(0x12u32)), 0x01120000);
+        assert_eq!(bits_from_target(U256::ZERO), 0);
+    }
+
+    #[test]
+    fn work_is_inverse_to_the_target() {
+        // Target 2^255 - 1 takes two hashes on average
+        assert_eq!(work(bits_from_target(U256::MAX >> 1)), 2);
+        assert_eq!(work(0x1d00ffff), 0x1_0001_0001);
+        assert!(work(0x1c00ffff) > work(0x1d00ffff));
+        // Nothing meets a zero target, its work saturates
+        assert_eq!(work(0x22010000), u128::MAX);
+    }
+
+    #[test]
+    fn retarget_scales_by_the_timespan() {
+        // Timespans dividing the target exactly, compact bits round otherwise
+        let bits = 0x1d00ffff;
+        assert_eq!(retarget(bits, 512, 512), bits);
+        assert_eq!(
+            target_from_bits(retarget(bits, 256, 512)),
+            target_from_bits(bits) / U256::from(2u32)
+        );
+        assert_eq!(
+            target_from_bits(retarget(bits, 1024, 512)),
+            target_from_bits(bits) * U256::from(2u32)
+        );
+    }
+
+    #[test]
+    fn retarget_clamps_to_a_factor_of_four() {
+        let bits = 0x1d00ffff;
+        assert_eq!(retarget(bits, 1, 600), retarget(bits, 150, 600));
+        assert_eq!(retarget(bits, 100_000, 600), retarget(bits, 2400, 600));
+        // Never easier than the easiest target
+        assert_eq!(retarget(MAX_TARGET_BITS, 2400, 600), MAX_TARGET_BITS);
+    }
+}
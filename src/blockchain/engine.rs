@@ -0,0 +1,434 @@
+//! Consensus engine picked at runtime.
+//!
+//! `Engine` wraps every consensus implementation behind one concrete type, so
+//! the node can take its engine from the chain file or the genesis config
+//! instead of fixing it at compile time. Blocks carry an `EngineProof`, which
+//! serializes exactly like the wrapped engine's own proof.
+
+use super::bft::{BftProof, BFT_PROOF_TAG};
+use super::consensus::{PowProof, SignedProof, SIGNED_PROOF_TAG, WORK_PROOF_TAG};
+use super::{
+    BftFinality, Block, BlockBody, BlockHeader, BlockRules, Consensus, ConsensusMessage,
+    MemoryHardProofOfWork, ProofOfAuthority, ProofOfStake, ProofOfWork, ValidationError,
+};
+use crate::utils::encoding::{Decode, DecodeError, Decoder, Encode};
+use core::fmt;
+use ed25519_dalek::SigningKey;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::future::Future;
+use std::pin::Pin;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_util::sync::CancellationToken;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EngineProof {
+    Work(PowProof),
+    Signed(SignedProof),
+    Bft(BftProof),
+}
+
+impl fmt::Display for EngineProof {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineProof::Work(proof) => write!(f, "{}", proof),
+            EngineProof::Signed(proof) => write!(f, "{}", proof),
+            EngineProof::Bft(proof) => write!(f, "{}", proof),
+        }
+    }
+}
+
+// Encoded exactly like the wrapped proof, whose leading tag tells them apart,
+// so block hashes do not depend on the wrapping
+impl Encode for EngineProof {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        match self {
+            EngineProof::Work(proof) => proof.encode_to(out),
+            EngineProof::Signed(proof) => proof.encode_to(out),
+            EngineProof::Bft(proof) => proof.encode_to(out),
+        }
+    }
+}
+
+impl Decode for EngineProof {
+    fn decode_from(input: &mut Decoder<'_>) -> Result<Self, DecodeError> {
+        match input.peek()? {
+            WORK_PROOF_TAG => Ok(EngineProof::Work(Decode::decode_from(input)?)),
+            SIGNED_PROOF_TAG => Ok(EngineProof::Signed(Decode::decode_from(input)?)),
+            BFT_PROOF_TAG => Ok(EngineProof::Bft(Decode::decode_from(input)?)),
+            tag => Err(DecodeError::UnknownTag { kind: "proof", tag }),
+        }
+    }
+}
+
+// Moves an engine's own proof in and out of `EngineProof`
+trait WrappedProof: Sized {
+    const KIND: &'static str;
+
+    fn unwrap_from(proof: &EngineProof) -> Option<Self>;
+
+    fn wrap(self) -> EngineProof;
+}
+
+impl WrappedProof for PowProof {
+    const KIND: &'static str = "work";
+
+    fn unwrap_from(proof: &EngineProof) -> Option<Self> {
+        match proof {
+            EngineProof::Work(proof) => Some(proof.clone()),
+            _ => None,
+        }
+    }
+
+    fn wrap(self) -> EngineProof {
+        EngineProof::Work(self)
+    }
+}
+
+impl WrappedProof for SignedProof {
+    const KIND: &'static str = "signed";
+
+    fn unwrap_from(proof: &EngineProof) -> Option<Self> {
+        match proof {
+            EngineProof::Signed(proof) => Some(proof.clone()),
+            _ => None,
+        }
+    }
+
+    fn wrap(self) -> EngineProof {
+        EngineProof::Signed(self)
+    }
+}
+
+impl WrappedProof for BftProof {
+    const KIND: &'static str = "bft";
+
+    fn unwrap_from(proof: &EngineProof) -> Option<Self> {
+        match proof {
+            EngineProof::Bft(proof) => Some(proof.clone()),
+            _ => None,
+        }
+    }
+
+    fn wrap(self) -> EngineProof {
+        EngineProof::Bft(self)
+    }
+}
+
+fn unwrap_header<P: WrappedProof>(
+    header: &BlockHeader<EngineProof>,
+) -> Result<BlockHeader<P>, ValidationError> {
+    match P::unwrap_from(&header.proof) {
+        Some(proof) => Ok(header.with_proof(proof)),
+        None => Err(ValidationError::WrongProofKind {
+            expected: P::KIND.to_string(),
+        }),
+    }
+}
+
+// The engines only look back over recent blocks, so only those are converted.
+// The oldest of them may be the last block before a consensus upgrade, sealed
+// by another engine; it stands in with `boundary_proof`, the engine's genesis
+// proof, as only its position and hash matter to the new engine.
+fn unwrap_history<P: WrappedProof>(
+    previous_headers: &[BlockHeader<EngineProof>],
+    history_len: usize,
+    boundary_proof: P,
+) -> Result<Vec<BlockHeader<P>>, ValidationError> {
+    let window_start = previous_headers.len().saturating_sub(history_len);
+    let window = &previous_headers[window_start..];
+    let Some((oldest, rest)) = window.split_first() else {
+        return Ok(Vec::new());
+    };
+
+    let oldest = unwrap_header(oldest).unwrap_or_else(|_| oldest.with_proof(boundary_proof));
+    std::iter::once(Ok(oldest))
+        .chain(rest.iter().map(unwrap_header))
+        .collect()
+}
+
+fn prove_with<'a, C>(
+    consensus: &'a C,
+    previous_headers: &'a [BlockHeader<EngineProof>],
+    timestamp: i64,
+    body: &'a BlockBody,
+    cancel: CancellationToken,
+) -> Pin<Box<dyn Future<Output = Option<EngineProof>> + Send + 'a>>
+where
+    C: Consensus,
+    C::Proof: WrappedProof,
+{
+    Box::pin(async move {
+        let history = unwrap_history(
+            previous_headers,
+            consensus.history_len(),
+            consensus.genesis_proof(),
+        )
+        .ok()?;
+        let proof = consensus.prove(&history, timestamp, body, cancel).await?;
+        Some(proof.wrap())
+    })
+}
+
+fn validate_with<C>(
+    consensus: &C,
+    previous_headers: &[BlockHeader<EngineProof>],
+    header: &BlockHeader<EngineProof>,
+) -> Result<(), ValidationError>
+where
+    C: Consensus,
+    C::Proof: WrappedProof,
+{
+    let history = unwrap_history(
+        previous_headers,
+        consensus.history_len(),
+        consensus.genesis_proof(),
+    )?;
+    consensus.validate_header(&history, &unwrap_header(header)?)
+}
+
+fn chain_weight_with<C>(consensus: &C, headers: &[BlockHeader<EngineProof>]) -> u128
+where
+    C: Consensus,
+    C::Proof: WrappedProof,
+{
+    let headers: Result<Vec<BlockHeader<C::Proof>>, _> =
+        headers.iter().map(unwrap_header).collect();
+    headers
+        .map(|headers| consensus.chain_weight(&headers))
+        .unwrap_or(0)
+}
+
+/// Any of the consensus engines, tagged with its `--consensus` name when
+/// stored in the chain file or the genesis config.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "engine")]
+pub enum Engine {
+    #[serde(rename = "pow")]
+    Work(ProofOfWork),
+    #[serde(rename = "mhpow")]
+    MemoryHardWork(MemoryHardProofOfWork),
+    #[serde(rename = "pos")]
+    Stake(ProofOfStake),
+    #[serde(rename = "poa")]
+    Authority(ProofOfAuthority),
+    #[serde(rename = "bft")]
+    Finality(BftFinality),
+}
+
+impl Engine {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Engine::Work(_) => "pow",
+            Engine::MemoryHardWork(_) => "mhpow",
+            Engine::Stake(_) => "pos",
+            Engine::Authority(_) => "poa",
+            Engine::Finality(_) => "bft",
+        }
+    }
+
+    /// Replaces the block rules, which the genesis config defines for the
+    /// whole network.
+    pub fn with_rules(mut self, rules: BlockRules) -> Self {
+        self.set_rules(rules);
+        self
+    }
+
+    /// Whether blocks are sealed with a validator key rather than mined.
+    pub fn is_signed(&self) -> bool {
+        matches!(
+            self,
+            Engine::Stake(_) | Engine::Authority(_) | Engine::Finality(_)
+        )
+    }
+
+    pub fn set_signing_key(&mut self, key: SigningKey) {
+        match self {
+            Engine::Stake(consensus) => consensus.set_signing_key(key),
+            Engine::Authority(consensus) => consensus.set_signing_key(key),
+            Engine::Finality(consensus) => consensus.set_signing_key(key),
+            Engine::Work(_) | Engine::MemoryHardWork(_) => {}
+        }
+    }
+
+    pub fn set_mining_threads(&mut self, threads: usize) {
+        match self {
+            Engine::Work(consensus) => consensus.set_mining_threads(threads),
+            Engine::MemoryHardWork(consensus) => consensus.set_mining_threads(threads),
+            Engine::Stake(_) | Engine::Authority(_) | Engine::Finality(_) => {}
+        }
+    }
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Engine::Work(consensus) => write!(f, "{} ({})", self.name(), consensus),
+            Engine::MemoryHardWork(consensus) => write!(f, "{} ({})", self.name(), consensus),
+            Engine::Stake(consensus) => write!(f, "{} ({})", self.name(), consensus),
+            Engine::Authority(consensus) => write!(f, "{} ({})", self.name(), consensus),
+            Engine::Finality(consensus) => write!(f, "{} ({})", self.name(), consensus),
+        }
+    }
+}
+
+impl Consensus for Engine {
+    type Proof = EngineProof;
+
+    fn prove<'a>(
+        &'a self,
+        previous_headers: &'a [BlockHeader<Self::Proof>],
+        timestamp: i64,
+        body: &'a BlockBody,
+        cancel: CancellationToken,
+    ) -> Pin<Box<dyn Future<Output = Option<Self::Proof>> + Send + 'a>> {
+        match self {
+            Engine::Work(consensus) => {
+                prove_with(consensus, previous_headers, timestamp, body, cancel)
+            }
+            Engine::MemoryHardWork(consensus) => {
+                prove_with(consensus, previous_headers, timestamp, body, cancel)
+            }
+            Engine::Stake(consensus) => {
+                prove_with(consensus, previous_headers, timestamp, body, cancel)
+            }
+            Engine::Authority(consensus) => {
+                prove_with(consensus, previous_headers, timestamp, body, cancel)
+            }
+            Engine::Finality(consensus) => {
+                prove_with(consensus, previous_headers, timestamp, body, cancel)
+            }
+        }
+    }
+
+    fn validate_header(
+        &self,
+        previous_headers: &[BlockHeader<Self::Proof>],
+        header: &BlockHeader<Self::Proof>,
+    ) -> Result<(), ValidationError> {
+        match self {
+            Engine::Work(consensus) => validate_with(consensus, previous_headers, header),
+            Engine::MemoryHardWork(consensus) => validate_with(consensus, previous_headers, header),
+            Engine::Stake(consensus) => validate_with(consensus, previous_headers, header),
+            Engine::Authority(consensus) => validate_with(consensus, previous_headers, header),
+            Engine::Finality(consensus) => validate_with(consensus, previous_headers, header),
+        }
+    }
+
+    fn rules(&self) -> &BlockRules {
+        match self {
+            Engine::Work(consensus) => consensus.rules(),
+            Engine::MemoryHardWork(consensus) => consensus.rules(),
+            Engine::Stake(consensus) => consensus.rules(),
+            Engine::Authority(consensus) => consensus.rules(),
+            Engine::Finality(consensus) => consensus.rules(),
+        }
+    }
+
+    fn set_rules(&mut self, rules: BlockRules) {
+        match self {
+            Engine::Work(consensus) => consensus.set_rules(rules),
+            Engine::MemoryHardWork(consensus) => consensus.set_rules(rules),
+            Engine::Stake(consensus) => consensus.set_rules(rules),
+            Engine::Authority(consensus) => consensus.set_rules(rules),
+            Engine::Finality(consensus) => consensus.set_rules(rules),
+        }
+    }
+
+    fn history_len(&self) -> usize {
+        match self {
+            Engine::Work(consensus) => consensus.history_len(),
+            Engine::MemoryHardWork(consensus) => consensus.history_len(),
+            Engine::Stake(consensus) => consensus.history_len(),
+            Engine::Authority(consensus) => consensus.history_len(),
+            Engine::Finality(consensus) => consensus.history_len(),
+        }
+    }
+
+    fn genesis_proof(&self) -> Self::Proof {
+        match self {
+            Engine::Work(consensus) => consensus.genesis_proof().wrap(),
+            Engine::MemoryHardWork(consensus) => consensus.genesis_proof().wrap(),
+            Engine::Stake(consensus) => consensus.genesis_proof().wrap(),
+            Engine::Authority(consensus) => consensus.genesis_proof().wrap(),
+            Engine::Finality(consensus) => consensus.genesis_proof().wrap(),
+        }
+    }
+
+    fn hashrate(&self) -> Option<u64> {
+        match self {
+            Engine::Work(consensus) => consensus.hashrate(),
+            Engine::MemoryHardWork(consensus) => consensus.hashrate(),
+            Engine::Stake(consensus) => consensus.hashrate(),
+            Engine::Authority(consensus) => consensus.hashrate(),
+            Engine::Finality(consensus) => consensus.hashrate(),
+        }
+    }
+
+    fn chain_weight(&self, headers: &[BlockHeader<Self::Proof>]) -> u128 {
+        match self {
+            Engine::Work(consensus) => chain_weight_with(consensus, headers),
+            Engine::MemoryHardWork(consensus) => chain_weight_with(consensus, headers),
+            Engine::Stake(consensus) => chain_weight_with(consensus, headers),
+            Engine::Authority(consensus) => chain_weight_with(consensus, headers),
+            Engine::Finality(consensus) => chain_weight_with(consensus, headers),
+        }
+    }
+
+    fn handle_message(
+        &self,
+        previous_headers: &[BlockHeader<Self::Proof>],
+        on_chain: &HashSet<String>,
+        message: ConsensusMessage,
+    ) -> Result<Option<Block<Self::Proof>>, ValidationError> {
+        let Engine::Finality(consensus) = self else {
+            return Err(ValidationError::UnexpectedMessage);
+        };
+        let history = unwrap_history(
+            previous_headers,
+            consensus.history_len(),
+            consensus.genesis_proof(),
+        )?;
+        let decided = consensus.handle_message(&history, on_chain, message)?;
+        Ok(decided.map(|block| block.with_proof(block.header.proof.clone().wrap())))
+    }
+
+    fn decides_by_vote(&self, _previous_headers: &[BlockHeader<Self::Proof>]) -> bool {
+        matches!(self, Engine::Finality(_))
+    }
+
+    fn set_outbox(&mut self, outbox: UnboundedSender<ConsensusMessage>) {
+        if let Engine::Finality(consensus) = self {
+            consensus.set_outbox(outbox);
+        }
+    }
+
+    // Only blocks voted on need a certificate
+    fn tip_certified(&self, headers: &[BlockHeader<Self::Proof>]) -> bool {
+        let (Engine::Finality(consensus), Some(tip)) = (self, headers.last()) else {
+            return true;
+        };
+        unwrap_header(tip).map_or(true, |tip| consensus.tip_certified(&[tip]))
+    }
+
+    fn tip_commit(&self, tip: &Block<Self::Proof>) -> Option<ConsensusMessage> {
+        let Engine::Finality(consensus) = self else {
+            return None;
+        };
+        let proof = BftProof::unwrap_from(&tip.header.proof)?;
+        consensus.tip_commit(&tip.with_proof(proof))
+    }
+
+    // Blocks of other engines are skipped
+    fn finalized_height(&self, headers: &[BlockHeader<Self::Proof>]) -> u64 {
+        let Engine::Finality(consensus) = self else {
+            return 0;
+        };
+        let headers: Vec<BlockHeader<BftProof>> = headers
+            .iter()
+            .filter_map(|header| unwrap_header(header).ok())
+            .collect();
+        consensus.finalized_height(&headers)
+    }
+}
@@ -0,0 +1,161 @@
+use super::{BlockHeader, ValidationError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Limits on how far synchronizing with peers may rewrite the chain, set in
+/// the genesis config so every node of a network enforces the same ones.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FinalityRules {
+    /// Block hashes every chain must contain, by height
+    #[serde(default)]
+    pub checkpoints: BTreeMap<u64, String>,
+    /// Most blocks a reorg may replace below the tip, unlimited when unset
+    #[serde(default)]
+    pub max_reorg_depth: Option<u64>,
+}
+
+impl FinalityRules {
+    /// Checks the block of `header` matches the checkpoint at its height, if
+    /// there is one.
+    pub fn check_block<P>(&self, header: &BlockHeader<P>) -> Result<(), ValidationError> {
+        match self.checkpoints.get(&header.index) {
+            Some(expected) if *expected != header.hash => {
+                Err(ValidationError::CheckpointMismatch {
+                    height: header.index,
+                    expected: expected.clone(),
+                    found: header.hash.clone(),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the chain of `headers` passes through every checkpoint it is long
+    /// enough to reach.
+    pub fn check_chain<P>(&self, headers: &[BlockHeader<P>]) -> Result<(), ValidationError> {
+        self.checkpoints
+            .keys()
+            .filter_map(|height| headers.get(*height as usize))
+            .try_for_each(|header| self.check_block(header))
+    }
+
+    /// Checks replacing `current` with `candidate` keeps `finalized_height`,
+    /// the newest block final under the consensus, and does not go deeper
+    /// than the maximum reorg depth.
+    pub fn check_reorg<P>(
+        &self,
+        current: &[BlockHeader<P>],
+        candidate: &[BlockHeader<P>],
+        finalized_height: u64,
+    ) -> Result<(), ValidationError> {
+        self.check_chain(candidate)?;
+
+        // Height of the first block the candidate replaces
+        let fork_height = current
+            .iter()
+            .zip(candidate)
+            .take_while(|(ours, theirs)| ours.hash == theirs.hash)
+            .count() as u64;
+        let depth = current.len() as u64 - fork_height;
+        if depth > 0 && fork_height <= finalized_height {
+            return Err(ValidationError::RevertsFinalizedBlock {
+                index: finalized_height,
+            });
+        }
+
+        match self.max_reorg_depth {
+            Some(max_depth) if depth > max_depth => {
+                Err(ValidationError::ReorgTooDeep { depth, max_depth })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Headers whose hashes are `hashes`, each linked to the one before
+    fn chain(hashes: &[&str]) -> Vec<BlockHeader<()>> {
+        let mut previous_hash = "0".to_string();
+        hashes
+            .iter()
+            .enumerate()
+            .map(|(index, hash)| {
+                let header = BlockHeader {
+                    version: 1,
+                    index: index as u64,
+                    timestamp: index as i64,
+                    merkle_root: String::new(),
+                    previous_hash: previous_hash.clone(),
+                    hash: hash.to_string(),
+                    proof: (),
+                };
+                previous_hash = hash.to_string();
+                header
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reorg_must_keep_checkpoints() {
+        let rules = FinalityRules {
+            checkpoints: BTreeMap::from([(1, "a1".to_string())]),
+            max_reorg_depth: None,
+        };
+        let current = chain(&["g", "a1", "a2"]);
+        let candidate = chain(&["g", "b1", "b2", "b3"]);
+        assert!(matches!(
+            rules.check_reorg(&current, &candidate, 0),
+            Err(ValidationError::CheckpointMismatch { height: 1, expected, found })
+                if expected == "a1" && found == "b1"
+        ));
+
+        // A candidate too short to reach a checkpoint is not held to it
+        let rules = FinalityRules {
+            checkpoints: BTreeMap::from([(5, "a5".to_string())]),
+            max_reorg_depth: None,
+        };
+        assert!(rules.check_reorg(&current, &candidate, 0).is_ok());
+    }
+
+    #[test]
+    fn reorg_depth_is_limited() {
+        let rules = FinalityRules {
+            checkpoints: BTreeMap::new(),
+            max_reorg_depth: Some(2),
+        };
+        let current = chain(&["g", "a1", "a2", "a3", "a4"]);
+
+        // Replaces a3 and a4, as deep as allowed
+        let shallow = chain(&["g", "a1", "a2", "b3", "b4", "b5"]);
+        assert!(rules.check_reorg(&current, &shallow, 0).is_ok());
+
+        // Replaces a2, a3 and a4
+        let deep = chain(&["g", "a1", "b2", "b3", "b4", "b5"]);
+        assert!(matches!(
+            rules.check_reorg(&current, &deep, 0),
+            Err(ValidationError::ReorgTooDeep {
+                depth: 3,
+                max_depth: 2
+            })
+        ));
+
+        // Extending the chain replaces nothing
+        let longer = chain(&["g", "a1", "a2", "a3", "a4", "a5"]);
+        assert!(rules.check_reorg(&current, &longer, 0).is_ok());
+    }
+
+    #[test]
+    fn reorg_keeps_finalized_blocks() {
+        let rules = FinalityRules::default();
+        let current = chain(&["g", "a1", "a2", "a3"]);
+        let candidate = chain(&["g", "a1", "b2", "b3", "b4"]);
+        assert!(rules.check_reorg(&current, &candidate, 1).is_ok());
+        assert!(matches!(
+            rules.check_reorg(&current, &candidate, 2),
+            Err(ValidationError::RevertsFinalizedBlock { index: 2 })
+        ));
+    }
+}
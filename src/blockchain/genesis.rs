@@ -0,0 +1,124 @@
+use super::{Block, BlockBody, BlockRules, Consensus, Engine, FinalityRules, Phase};
+use crate::utils::encoding::{Decode, DecodeError, Decoder, Encode};
+use crate::utils::HashAlgorithm;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+
+pub const GENESIS_CHAIN_ID: &str = "rustchain";
+pub const GENESIS_DATA: &str = "Fiat Lux";
+// 2025-03-01T00:00:00Z
+pub const GENESIS_TIMESTAMP: i64 = 1_740_787_200;
+
+/// Network genesis configuration, shared between all nodes of a network as a
+/// JSON file so they derive the same block 0.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenesisConfig {
+    pub chain_id: String,
+    pub timestamp: i64,
+    pub data: String,
+    /// Initial amounts per public key, used as initial stakes by proof of stake
+    #[serde(default)]
+    pub allocations: BTreeMap<String, u64>,
+    #[serde(default)]
+    pub rules: BlockRules,
+    /// Consensus engine of the network, nodes fall back to their command line
+    /// flags when it is not set
+    #[serde(default)]
+    pub consensus: Option<Engine>,
+    /// Consensus engines taking over at later block heights
+    #[serde(default)]
+    pub upgrades: Vec<Phase>,
+    /// Checkpoints and reorg depth limit enforced when syncing with peers
+    #[serde(default)]
+    pub finality: FinalityRules,
+}
+
+impl Default for GenesisConfig {
+    fn default() -> Self {
+        GenesisConfig {
+            chain_id: GENESIS_CHAIN_ID.to_string(),
+            timestamp: GENESIS_TIMESTAMP,
+            data: GENESIS_DATA.to_string(),
+            allocations: BTreeMap::new(),
+            rules: BlockRules::default(),
+            consensus: None,
+            upgrades: Vec::new(),
+            finality: FinalityRules::default(),
+        }
+    }
+}
+
+/// What the genesis block commits to besides its proof, carried in its body.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenesisData {
+    pub chain_id: String,
+    pub message: String,
+    pub allocations: BTreeMap<String, u64>,
+}
+
+impl Encode for GenesisData {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.chain_id.encode_to(out);
+        self.message.encode_to(out);
+        self.allocations.encode_to(out);
+    }
+}
+
+impl Decode for GenesisData {
+    fn decode_from(input: &mut Decoder<'_>) -> Result<Self, DecodeError> {
+        Ok(GenesisData {
+            chain_id: Decode::decode_from(input)?,
+            message: Decode::decode_from(input)?,
+            allocations: Decode::decode_from(input)?,
+        })
+    }
+}
+
+impl GenesisConfig {
+    pub fn load(path: &str) -> std::io::Result<Self> {
+        let file = File::open(path)?;
+        let config = serde_json::from_reader(file)?;
+        Ok(config)
+    }
+
+    /// Genesis spec for this network, sealed with the consensus' genesis proof.
+    pub fn spec<C: Consensus>(&self, consensus: &C) -> GenesisSpec<C::Proof> {
+        GenesisSpec {
+            data: GenesisData {
+                chain_id: self.chain_id.clone(),
+                message: self.data.clone(),
+                allocations: self.allocations.clone(),
+            },
+            timestamp: self.timestamp,
+            proof: consensus.genesis_proof(),
+            hash: consensus.rules().hash,
+        }
+    }
+}
+
+/// Everything that goes into block 0. Nodes built from the same spec share an
+/// identical genesis block and therefore the same genesis hash.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenesisSpec<P> {
+    pub data: GenesisData,
+    pub timestamp: i64,
+    pub proof: P,
+    pub hash: HashAlgorithm,
+}
+
+impl<P: Clone + Encode> GenesisSpec<P> {
+    pub fn block(&self) -> Block<P> {
+        Block::new(
+            0,
+            BlockBody {
+                transactions: Vec::new(),
+                genesis: Some(self.data.clone()),
+            },
+            self.timestamp,
+            self.proof.clone(),
+            "0".to_string(),
+            self.hash,
+        )
+    }
+}
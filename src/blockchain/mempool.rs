@@ -1,61 +1,152 @@
+use super::{BlockBody, BlockRules, Chain, Consensus};
+use crate::utils::encoding::{Decode, DecodeError, Decoder, Encode};
+use crate::utils::keys;
 use chrono::Utc;
+use ed25519_dalek::SigningKey;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::Mutex as TokioMutex;
 use tokio::sync::{mpsc, oneshot};
-use uuid;
+
+// Leads every signed payload, so a transaction signature cannot be replayed
+// as a block seal or vote made with the same key
+const TRANSACTION_DOMAIN: &str = "rustchain transaction";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageTransaction {
     pub id: String,
+    /// Public key hex of the signer
+    pub sender: String,
     pub message: String,
     pub timestamp: i64,
+    /// When a node accepted the transaction, not covered by the signature
     pub submitted_at: i64,
+    /// Ed25519 signature hex over `signing_payload`
+    pub signature: String,
+}
+
+impl Encode for MessageTransaction {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.id.encode_to(out);
+        self.sender.encode_to(out);
+        self.message.encode_to(out);
+        self.timestamp.encode_to(out);
+        self.submitted_at.encode_to(out);
+        self.signature.encode_to(out);
+    }
+}
+
+impl Decode for MessageTransaction {
+    fn decode_from(input: &mut Decoder<'_>) -> Result<Self, DecodeError> {
+        Ok(MessageTransaction {
+            id: Decode::decode_from(input)?,
+            sender: Decode::decode_from(input)?,
+            message: Decode::decode_from(input)?,
+            timestamp: Decode::decode_from(input)?,
+            submitted_at: Decode::decode_from(input)?,
+            signature: Decode::decode_from(input)?,
+        })
+    }
 }
 
 impl MessageTransaction {
-    pub fn new(message: String) -> Self {
-        let id = uuid::Uuid::new_v4().to_string();
+    /// New transaction carrying `message`, signed by `key`.
+    pub fn signed(key: &SigningKey, message: String) -> Self {
         let now = Utc::now().timestamp();
-
-        Self {
-            id,
+        let mut transaction = Self {
+            id: String::new(),
+            sender: keys::public_key_hex(key),
             message,
             timestamp: now,
             submitted_at: now,
+            signature: String::new(),
+        };
+        transaction.id = transaction.derived_id();
+        transaction.signature = keys::sign(key, &transaction.signing_payload());
+        transaction
+    }
+
+    /// Canonical encoding of what the sender signs: the domain, then sender,
+    /// message and timestamp.
+    pub fn signing_payload(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        TRANSACTION_DOMAIN.encode_to(&mut payload);
+        self.sender.encode_to(&mut payload);
+        self.message.encode_to(&mut payload);
+        self.timestamp.encode_to(&mut payload);
+        payload
+    }
+
+    /// Id the transaction has to carry, the SHA-256 hex of its signing
+    /// payload, so the same signed message cannot come under a second id.
+    pub fn derived_id(&self) -> String {
+        hex::encode(Sha256::digest(self.signing_payload()))
+    }
+
+    /// Why the transaction could not go into any block, if it could not:
+    /// it lacks an id or a message, its id is not the one derived from its
+    /// content, or its sender's signature does not hold. Checked both when
+    /// admitting it to the mempool and when validating a block carrying it.
+    pub fn validity_error(&self) -> Option<&'static str> {
+        if self.id.is_empty() {
+            Some("has no id")
+        } else if self.message.is_empty() {
+            Some("has an empty message")
+        } else if self.id != self.derived_id() {
+            Some("has an id other than the hash of its signed content")
+        } else if self.sender.is_empty() || self.signature.is_empty() {
+            Some("is not signed")
+        } else if !keys::verify(&self.sender, &self.signing_payload(), &self.signature) {
+            Some("has an invalid signature")
+        } else {
+            None
         }
     }
 }
 
+type Submission = (MessageTransaction, oneshot::Sender<Result<(), String>>);
+
 #[derive(Clone)]
 pub struct MessageQueue {
-    sender: mpsc::Sender<(String, oneshot::Sender<()>)>,
+    sender: mpsc::Sender<Submission>,
 }
 
 impl MessageQueue {
-    pub fn new(mempool: Arc<TokioMutex<Mempool>>) -> Self {
-        let (tx, mut rx) = mpsc::channel::<(String, oneshot::Sender<()>)>(100);
+    /// Feeds submissions to `mempool`, which admits those fitting in a block
+    /// under the rules of `chain` and not in it yet.
+    pub fn new<C: Consensus>(
+        mempool: Arc<TokioMutex<Mempool>>,
+        chain: Arc<TokioMutex<Chain<C>>>,
+    ) -> Self {
+        let (tx, mut rx) = mpsc::channel::<Submission>(100);
 
         tokio::spawn(async move {
-            while let Some((message, confirmation)) = rx.recv().await {
+            while let Some((transaction, confirmation)) = rx.recv().await {
+                let chain = chain.lock().await;
                 let mut pool = mempool.lock().await;
-                let _ = pool.add_message(message);
-                let _ = confirmation.send(());
+                let added = pool
+                    .add_message(
+                        transaction,
+                        chain.consensus.rules(),
+                        chain.transaction_ids(),
+                    )
+                    .map(|_| ());
+                let _ = confirmation.send(added);
             }
         });
         MessageQueue { sender: tx }
     }
 
-    pub async fn submit_message(&self, message: String) -> Result<(), String> {
+    /// Queues `transaction` for the mempool, failing if the mempool refuses it.
+    pub async fn submit_message(&self, transaction: MessageTransaction) -> Result<(), String> {
         let (tx, rx) = oneshot::channel();
-        match self.sender.send((message, tx)).await {
-            Ok(_) => {
-                rx.await
-                    .map_err(|_| "Failed to receive queuing confirmation".to_string())?;
-                Ok(())
-            }
+        match self.sender.send((transaction, tx)).await {
+            Ok(_) => rx
+                .await
+                .map_err(|_| "Failed to receive queuing confirmation".to_string())?,
             Err(_) => Err("Failed to send queue message".to_string()),
         }
     }
@@ -80,14 +171,38 @@ impl Mempool {
             message_timeout: Duration::from_secs(timeout_secs),
         }
     }
-    pub fn add_message(&mut self, message: String) -> Result<MessageTransaction, String> {
+    pub fn add_message(
+        &mut self,
+        transaction: MessageTransaction,
+        rules: &BlockRules,
+        on_chain: &HashSet<String>,
+    ) -> Result<MessageTransaction, String> {
+        if let Some(reason) = transaction.validity_error() {
+            return Err(format!("Transaction {} {}", transaction.id, reason));
+        }
+        if on_chain.contains(&transaction.id) {
+            return Err(format!(
+                "Transaction {} is already in the chain",
+                transaction.id
+            ));
+        }
+        // No block could ever take it, it would only hold a slot until expiry
+        let size = BlockBody::default().encode().len() + transaction.encode().len();
+        if size > rules.max_block_bytes {
+            return Err(format!(
+                "Transaction {} needs a block of {} bytes, more than the limit of {}",
+                transaction.id, size, rules.max_block_bytes
+            ));
+        }
+        if self.pending_messages.contains_key(&transaction.id) {
+            return Err(format!("Transaction {} is already pending", transaction.id));
+        }
         if self.pending_messages.len() > self.max_size {
             self.clean_expired_messages();
             if self.pending_messages.len() > self.max_size {
                 return Err("Mempool is full".to_string());
             }
         }
-        let transaction = MessageTransaction::new(message);
         self.pending_messages
             .insert(transaction.id.clone(), transaction.clone());
         self.message_added_at
@@ -95,12 +210,31 @@ impl Mempool {
         Ok(transaction)
     }
 
-    pub fn get_pending_messages(&self, limit: usize) -> Vec<MessageTransaction> {
-        self.pending_messages
-            .values()
-            .cloned()
-            .take(limit)
-            .collect()
+    /// Pending messages for the next block, as many as fit within the block
+    /// size and transaction count limits of `rules`, leaving out those
+    /// already `on_chain`.
+    pub fn get_block_messages(
+        &self,
+        rules: &BlockRules,
+        on_chain: &HashSet<String>,
+    ) -> Vec<MessageTransaction> {
+        let mut size = BlockBody::default().encode().len();
+        let mut messages = Vec::new();
+        for transaction in self.pending_messages.values() {
+            if messages.len() == rules.max_block_transactions {
+                break;
+            }
+            if on_chain.contains(&transaction.id) {
+                continue;
+            }
+            // Messages too large for what is left wait for a later block
+            let transaction_size = transaction.encode().len();
+            if size + transaction_size <= rules.max_block_bytes {
+                size += transaction_size;
+                messages.push(transaction.clone());
+            }
+        }
+        messages
     }
 
     pub fn remove_messages(&mut self, ids: &[String]) {
@@ -115,6 +249,20 @@ impl Mempool {
         );
     }
 
+    /// Drops the pending messages that are `on_chain` by now, as after
+    /// adopting a peer's chain.
+    pub fn prune(&mut self, on_chain: &HashSet<String>) {
+        let ids: Vec<String> = self
+            .pending_messages
+            .keys()
+            .filter(|id| on_chain.contains(*id))
+            .cloned()
+            .collect();
+        if !ids.is_empty() {
+            self.remove_messages(&ids);
+        }
+    }
+
     pub fn clean_expired_messages(&mut self) {
         let now = Instant::now();
         let expired_ids: Vec<String> = self
@@ -134,3 +282,70 @@ impl Mempool {
         self.pending_messages.len()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admit(transaction: MessageTransaction) -> Result<MessageTransaction, String> {
+        Mempool::new(10, 60).add_message(transaction, &BlockRules::default(), &HashSet::new())
+    }
+
+    #[test]
+    fn admits_only_transactions_signed_by_their_sender() {
+        let key = SigningKey::from_bytes(&[0x11; 32]);
+        let signed = MessageTransaction::signed(&key, "hello".to_string());
+        assert_eq!(signed.id, signed.derived_id());
+        assert!(admit(signed.clone()).is_ok());
+
+        let mut tampered = signed.clone();
+        tampered.message = "hello there".to_string();
+        tampered.id = tampered.derived_id();
+        assert!(admit(tampered)
+            .unwrap_err()
+            .ends_with("has an invalid signature"));
+
+        let mut unsigned = signed.clone();
+        unsigned.signature = String::new();
+        assert!(admit(unsigned).unwrap_err().ends_with("is not signed"));
+
+        let mut misnamed = signed;
+        misnamed.id = "5f0c3d9e-1b2a-4c6d-8e7f-000000000001".to_string();
+        assert!(admit(misnamed)
+            .unwrap_err()
+            .ends_with("has an id other than the hash of its signed content"));
+    }
+
+    #[test]
+    fn empty_message_is_refused_at_admission() {
+        let key = SigningKey::from_bytes(&[0x11; 32]);
+        let empty = MessageTransaction::signed(&key, String::new());
+        assert!(admit(empty).unwrap_err().ends_with("has an empty message"));
+
+        let mut unnamed = MessageTransaction::signed(&key, "hello".to_string());
+        unnamed.id = String::new();
+        assert!(admit(unnamed).unwrap_err().ends_with("has no id"));
+    }
+
+    #[test]
+    fn transactions_on_chain_are_not_mined_again() {
+        let key = SigningKey::from_bytes(&[0x11; 32]);
+        let rules = BlockRules::default();
+        let mut mempool = Mempool::new(10, 60);
+        let mined = MessageTransaction::signed(&key, "mined".to_string());
+        let pending = MessageTransaction::signed(&key, "pending".to_string());
+        for transaction in [mined.clone(), pending.clone()] {
+            mempool
+                .add_message(transaction, &rules, &HashSet::new())
+                .unwrap();
+        }
+
+        let on_chain = HashSet::from([mined.id]);
+        let messages = mempool.get_block_messages(&rules, &on_chain);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, pending.id);
+
+        mempool.prune(&on_chain);
+        assert_eq!(mempool.pending_count(), 1);
+    }
+}
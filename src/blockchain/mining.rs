@@ -1,12 +1,17 @@
-use crate::blockchain::{Block, Consensus, MessageTransaction};
+use crate::blockchain::{Block, BlockBody, BlockHeader, Chain, Consensus, MessageTransaction};
 use serde::Serialize;
 use std::sync::Arc;
 use tokio::sync::mpsc::{self, Receiver, Sender};
 use tokio::sync::Mutex;
 use tokio::time::{sleep, Duration};
+use tokio_util::sync::CancellationToken;
 
 use super::Mempool;
 
+// Least history kept for consensus rules that look back over recent blocks,
+// engines configured to look further back ask for more
+pub const RECENT_BLOCKS_WINDOW: usize = 64;
+
 pub enum MiningCommand {
     StartMining,
     StopMining,
@@ -15,19 +20,60 @@ pub enum MiningCommand {
 
 pub struct MiningInterface<C: Consensus> {
     pub mempool_accessor: Arc<Mutex<Mempool>>,
-    pub chain_info: Arc<Mutex<ChainInfo>>,
+    // Only read for the ids of the transactions already in it
+    pub chain: Arc<Mutex<Chain<C>>>,
+    pub chain_info: Arc<Mutex<ChainInfo<C::Proof>>>,
     pub consensus: C,
     pub block_channel: mpsc::Sender<(Block<C::Proof>, Vec<String>)>,
 }
 
-pub struct ChainInfo {
+pub struct ChainInfo<P> {
     pub length: u64,
     pub last_hash: String,
+    pub recent_headers: Vec<BlockHeader<P>>,
+    // How many headers `recent_headers` keeps, the consensus' history length
+    history_len: usize,
+    // Cancelled whenever the tip changes, so in-flight mining on the old tip stops
+    tip_changed: CancellationToken,
+}
+
+impl<P: Clone> ChainInfo<P> {
+    pub fn new(chain: &[Block<P>], history_len: usize) -> Self {
+        let mut info = ChainInfo {
+            length: 0,
+            last_hash: String::new(),
+            recent_headers: Vec::new(),
+            history_len,
+            tip_changed: CancellationToken::new(),
+        };
+        info.update(chain);
+        info
+    }
+
+    pub fn update(&mut self, chain: &[Block<P>]) {
+        let last_hash = chain
+            .last()
+            .map(|block| block.header.hash.clone())
+            .unwrap_or_default();
+        if last_hash != self.last_hash {
+            self.tip_changed.cancel();
+            self.tip_changed = CancellationToken::new();
+        }
+
+        self.length = chain.len() as u64;
+        self.last_hash = last_hash;
+        let window_start = chain.len().saturating_sub(self.history_len);
+        self.recent_headers = chain[window_start..]
+            .iter()
+            .map(|block| block.header.clone())
+            .collect();
+    }
 }
 
 pub struct MiningCoordinator<C: Consensus> {
     command_rx: Receiver<MiningCommand>,
     mining_interface: MiningInterface<C>,
+    #[allow(dead_code)]
     accumulation_time_ms: u64,
     is_mining: bool,
 }
@@ -52,33 +98,78 @@ where
         )
     }
 
+    // Applies a command, returns false if the coordinator should shut down
+    fn handle_command(&mut self, command: MiningCommand) -> bool {
+        match command {
+            MiningCommand::StartMining => {
+                if !self.is_mining {
+                    println!("Start mining process");
+                }
+                self.is_mining = true;
+            }
+            MiningCommand::StopMining => {
+                println!("Stopping mining process");
+                self.is_mining = false;
+            }
+            MiningCommand::Shutdown => {
+                println!("Shutting down mining coordinator");
+                return false;
+            }
+        }
+        true
+    }
+
     pub async fn run(&mut self) {
         loop {
             while let Ok(command) = self.command_rx.try_recv() {
-                match command {
-                    MiningCommand::StartMining => {
-                        println!("Start mining process");
-                        self.is_mining = true;
-                    }
-                    MiningCommand::StopMining => {
-                        println!("Stopping mining process");
-                        self.is_mining = false;
-                    }
-                    MiningCommand::Shutdown => {
-                        println!("Shutting down mining coordinator");
-                        return;
-                    }
+                if !self.handle_command(command) {
+                    return;
                 }
             }
             if self.is_mining {
+                // Taken before the messages, so a block added in between
+                // cancels the search instead of it repeating transactions
+                let cancel = {
+                    let chain_info = self.mining_interface.chain_info.lock().await;
+                    chain_info.tip_changed.child_token()
+                };
                 let messages = {
+                    let chain = self.mining_interface.chain.lock().await;
                     let mempool = self.mining_interface.mempool_accessor.lock().await;
-                    // TODO: You guessed it, parametrize
-                    mempool.get_pending_messages(10)
+                    mempool.get_block_messages(
+                        self.mining_interface.consensus.rules(),
+                        chain.transaction_ids(),
+                    )
                 };
 
                 if !messages.is_empty() {
-                    if let Some((block, message_ids)) = self.mine_block(&messages).await {
+                    let mining = Self::mine_block(
+                        self.mining_interface.chain_info.clone(),
+                        self.mining_interface.consensus.clone(),
+                        messages,
+                        cancel.clone(),
+                    );
+                    tokio::pin!(mining);
+
+                    // Keep listening for commands while mining so stopping or
+                    // shutting down does not wait for the proof search
+                    let mined = loop {
+                        tokio::select! {
+                            mined = &mut mining => break mined,
+                            Some(command) = self.command_rx.recv() => {
+                                if !self.handle_command(command) {
+                                    cancel.cancel();
+                                    return;
+                                }
+                                if !self.is_mining {
+                                    cancel.cancel();
+                                    break None;
+                                }
+                            }
+                        }
+                    };
+
+                    if let Some((block, message_ids)) = mined {
                         if let Err(e) = self
                             .mining_interface
                             .block_channel
@@ -87,8 +178,11 @@ where
                         {
                             eprintln!("Error sending mined block: {}", e);
                         } else {
-                            println!("Successfully mined block #{}", block.index);
+                            println!("Successfully mined block #{}", block.header.index);
                         }
+                    } else if !cancel.is_cancelled() {
+                        // Not our turn to produce the next block, wait for the tip to move
+                        sleep(Duration::from_millis(500)).await;
                     }
                 } else {
                     // No messages to mine, pause to avoid
@@ -103,29 +197,40 @@ where
     }
 
     async fn mine_block(
-        &self,
-        messages: &[MessageTransaction],
+        chain_info: Arc<Mutex<ChainInfo<C::Proof>>>,
+        consensus: C,
+        messages: Vec<MessageTransaction>,
+        cancel: CancellationToken,
     ) -> Option<(Block<C::Proof>, Vec<String>)> {
         if messages.is_empty() {
             return None;
         }
 
-        let (chain_len, prev_hash) = {
-            let chain_info = self.mining_interface.chain_info.lock().await;
-            (chain_info.length, chain_info.last_hash.clone())
+        let recent_headers = {
+            let chain_info = chain_info.lock().await;
+            chain_info.recent_headers.clone()
         };
 
-        let data = serde_json::to_string(&messages).unwrap_or_default();
-        let timestamp = chrono::Utc::now().timestamp();
-        let consensus = self.mining_interface.consensus.clone();
+        let body = BlockBody::new(messages);
+        let timestamp = chrono::Utc::now()
+            .timestamp()
+            .max(consensus.min_timestamp(&recent_headers));
 
         let proof = consensus
-            .prove(chain_len, timestamp, &data, &prev_hash)
-            .await;
+            .prove(&recent_headers, timestamp, &body, cancel)
+            .await?;
 
-        let block = Block::new(chain_len, data, timestamp, proof, prev_hash);
+        let parent = recent_headers.last()?;
+        let block = Block::new(
+            parent.index + 1,
+            body,
+            timestamp,
+            proof,
+            parent.hash.clone(),
+            consensus.rules().hash,
+        );
 
-        let message_ids: Vec<String> = messages.iter().map(|tx| tx.id.clone()).collect();
+        let message_ids = block.body.transaction_ids();
 
         Some((block, message_ids))
     }
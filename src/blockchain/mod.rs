@@ -1,11 +1,27 @@
+pub mod bft;
 pub mod block;
 pub mod chain;
 pub mod consensus;
+pub mod difficulty;
+pub mod engine;
+pub mod finality;
+pub mod genesis;
 pub mod mempool;
 pub mod mining;
+pub mod schedule;
+pub mod validation;
 
-pub use block::Block;
+pub use bft::{BftFinality, ConsensusMessage};
+pub use block::{Block, BlockBody, BlockHeader};
 pub use chain::Chain;
-pub use consensus::{Consensus, ProofOfWork};
+pub use consensus::{
+    BlockRules, Consensus, MemoryHardProofOfWork, ProofOfAuthority, ProofOfStake, ProofOfWork,
+    Retargeting,
+};
+pub use engine::Engine;
+pub use finality::FinalityRules;
+pub use genesis::{GenesisConfig, GenesisData};
 pub use mempool::{Mempool, MessageQueue, MessageTransaction};
 pub use mining::{ChainInfo, MiningCommand, MiningCoordinator, MiningInterface};
+pub use schedule::{ConsensusSchedule, Phase};
+pub use validation::ValidationError;
@@ -0,0 +1,393 @@
+//! Consensus upgrades at fixed block heights.
+//!
+//! A `ConsensusSchedule` is a list of phases, each running one `Engine` from
+//! its starting height until the next phase begins. Blocks are validated and
+//! produced by the engine of the phase they fall in, which only sees history
+//! from its own phase plus the last block before it.
+
+use super::consensus::validate_genesis;
+use super::engine::EngineProof;
+use super::{
+    Block, BlockBody, BlockHeader, BlockRules, Consensus, ConsensusMessage, Engine, ValidationError,
+};
+use core::fmt;
+use ed25519_dalek::SigningKey;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::future::Future;
+use std::pin::Pin;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_util::sync::CancellationToken;
+
+/// An engine taking over from `from_height` on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Phase {
+    pub from_height: u64,
+    #[serde(flatten)]
+    pub engine: Engine,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConsensusSchedule {
+    // Sorted by height, the first phase starts at genesis
+    phases: Vec<Phase>,
+}
+
+impl ConsensusSchedule {
+    /// Runs `initial` from genesis, switching to each of `upgrades` at its height.
+    pub fn new(initial: Engine, mut upgrades: Vec<Phase>) -> Self {
+        upgrades.sort_by_key(|phase| phase.from_height);
+        if let Some(phase) = upgrades.iter().find(|phase| phase.from_height == 0) {
+            panic!(
+                "Consensus upgrade to {} must start after the genesis block",
+                phase.engine.name()
+            );
+        }
+
+        let mut phases = vec![Phase {
+            from_height: 0,
+            engine: initial,
+        }];
+        phases.extend(upgrades);
+        ConsensusSchedule { phases }
+    }
+
+    /// Replaces the upgrades following the initial engine.
+    pub fn with_upgrades(self, upgrades: Vec<Phase>) -> Self {
+        let initial = self
+            .phases
+            .into_iter()
+            .next()
+            .expect("Schedule has a genesis phase");
+        ConsensusSchedule::new(initial.engine, upgrades)
+    }
+
+    fn phase_at(&self, height: u64) -> &Phase {
+        self.phases
+            .iter()
+            .rev()
+            .find(|phase| phase.from_height <= height)
+            .unwrap_or(&self.phases[0])
+    }
+
+    /// Engine producing and validating the block at `height`.
+    pub fn engine_at(&self, height: u64) -> &Engine {
+        &self.phase_at(height).engine
+    }
+
+    /// Whether any phase seals blocks with a validator key.
+    pub fn is_signed(&self) -> bool {
+        self.phases.iter().any(|phase| phase.engine.is_signed())
+    }
+
+    pub fn set_signing_key(&mut self, key: SigningKey) {
+        for phase in &mut self.phases {
+            phase.engine.set_signing_key(key.clone());
+        }
+    }
+
+    pub fn set_mining_threads(&mut self, threads: usize) {
+        for phase in &mut self.phases {
+            phase.engine.set_mining_threads(threads);
+        }
+    }
+
+    // Headers of `chain` falling in the phase at `position`
+    fn phase_headers(
+        &self,
+        position: usize,
+        chain: &[BlockHeader<EngineProof>],
+    ) -> Vec<BlockHeader<EngineProof>> {
+        let start = self.phases[position].from_height;
+        let end = self
+            .phases
+            .get(position + 1)
+            .map_or(u64::MAX, |next| next.from_height);
+        chain
+            .iter()
+            .filter(|header| header.index >= start && header.index < end)
+            .cloned()
+            .collect()
+    }
+}
+
+// Engine for the block following `previous_headers`, along with the part of
+// them it may see: its own phase and the block right before it
+fn for_next_block<'a>(
+    schedule: &'a ConsensusSchedule,
+    previous_headers: &'a [BlockHeader<EngineProof>],
+) -> (&'a Engine, &'a [BlockHeader<EngineProof>]) {
+    let height = previous_headers.last().map_or(0, |parent| parent.index + 1);
+    let phase = schedule.phase_at(height);
+    let start = previous_headers
+        .iter()
+        .position(|header| header.index + 1 >= phase.from_height)
+        .unwrap_or(previous_headers.len());
+    (&phase.engine, &previous_headers[start..])
+}
+
+impl fmt::Display for ConsensusSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, phase) in self.phases.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", then from height {} ", phase.from_height)?;
+            }
+            write!(f, "{}", phase.engine)?;
+        }
+        Ok(())
+    }
+}
+
+impl Consensus for ConsensusSchedule {
+    type Proof = EngineProof;
+
+    fn prove<'a>(
+        &'a self,
+        previous_headers: &'a [BlockHeader<Self::Proof>],
+        timestamp: i64,
+        body: &'a BlockBody,
+        cancel: CancellationToken,
+    ) -> Pin<Box<dyn Future<Output = Option<Self::Proof>> + Send + 'a>> {
+        let (engine, history) = for_next_block(self, previous_headers);
+        engine.prove(history, timestamp, body, cancel)
+    }
+
+    fn validate_header(
+        &self,
+        previous_headers: &[BlockHeader<Self::Proof>],
+        header: &BlockHeader<Self::Proof>,
+    ) -> Result<(), ValidationError> {
+        let (engine, history) = for_next_block(self, previous_headers);
+        engine.validate_header(history, header)
+    }
+
+    // Walks the phases along with the headers, instead of looking up the
+    // phase of every block and its history in the headers before it
+    fn validate_headers(
+        &self,
+        genesis_hash: &str,
+        headers: &[BlockHeader<Self::Proof>],
+    ) -> Result<(), ValidationError> {
+        let Some(genesis) = headers.first() else {
+            return Ok(());
+        };
+        validate_genesis(self.rules().hash, genesis_hash, genesis)?;
+
+        let mut position = 0;
+        // First header the engine of the current phase sees
+        let mut start = 0;
+        for i in 1..headers.len() {
+            let height = headers[i - 1].index + 1;
+            while self
+                .phases
+                .get(position + 1)
+                .is_some_and(|next| next.from_height <= height)
+            {
+                position += 1;
+                start = i - 1;
+            }
+            self.phases[position]
+                .engine
+                .validate_header(&headers[start..i], &headers[i])
+                .map_err(|cause| ValidationError::InvalidBlock {
+                    index: headers[i].index,
+                    cause: Box::new(cause),
+                })?;
+        }
+        Ok(())
+    }
+
+    // Every phase follows the network's rules from the genesis config
+    fn rules(&self) -> &BlockRules {
+        self.phases[0].engine.rules()
+    }
+
+    fn set_rules(&mut self, rules: BlockRules) {
+        for phase in &mut self.phases {
+            phase.engine.set_rules(rules.clone());
+        }
+    }
+
+    fn history_len(&self) -> usize {
+        self.phases
+            .iter()
+            .map(|phase| phase.engine.history_len())
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn min_timestamp(&self, previous_headers: &[BlockHeader<Self::Proof>]) -> i64 {
+        let (engine, history) = for_next_block(self, previous_headers);
+        engine.min_timestamp(history)
+    }
+
+    fn genesis_proof(&self) -> Self::Proof {
+        self.phases[0].engine.genesis_proof()
+    }
+
+    fn hashrate(&self) -> Option<u64> {
+        self.phases.iter().find_map(|phase| phase.engine.hashrate())
+    }
+
+    // Each phase weighs its own blocks with its engine's measure
+    fn chain_weight(&self, headers: &[BlockHeader<Self::Proof>]) -> u128 {
+        self.phases
+            .iter()
+            .enumerate()
+            .map(|(i, phase)| phase.engine.chain_weight(&self.phase_headers(i, headers)))
+            .fold(0u128, |total, weight| total.saturating_add(weight))
+    }
+
+    fn handle_message(
+        &self,
+        previous_headers: &[BlockHeader<Self::Proof>],
+        on_chain: &HashSet<String>,
+        message: ConsensusMessage,
+    ) -> Result<Option<Block<Self::Proof>>, ValidationError> {
+        let (engine, history) = for_next_block(self, previous_headers);
+        engine.handle_message(history, on_chain, message)
+    }
+
+    fn decides_by_vote(&self, previous_headers: &[BlockHeader<Self::Proof>]) -> bool {
+        let (engine, history) = for_next_block(self, previous_headers);
+        engine.decides_by_vote(history)
+    }
+
+    fn set_outbox(&mut self, outbox: UnboundedSender<ConsensusMessage>) {
+        for phase in &mut self.phases {
+            phase.engine.set_outbox(outbox.clone());
+        }
+    }
+
+    // The tip is certified by the rules of the phase it was made in
+    fn tip_certified(&self, headers: &[BlockHeader<Self::Proof>]) -> bool {
+        headers.last().is_none_or(|tip| {
+            self.engine_at(tip.index)
+                .tip_certified(std::slice::from_ref(tip))
+        })
+    }
+
+    fn tip_commit(&self, tip: &Block<Self::Proof>) -> Option<ConsensusMessage> {
+        self.engine_at(tip.header.index).tip_commit(tip)
+    }
+
+    // Blocks finalized in an earlier phase stay final after an upgrade
+    fn finalized_height(&self, headers: &[BlockHeader<Self::Proof>]) -> u64 {
+        self.phases
+            .iter()
+            .enumerate()
+            .map(|(i, phase)| {
+                phase
+                    .engine
+                    .finalized_height(&self.phase_headers(i, headers))
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::genesis::GENESIS_TIMESTAMP;
+    use crate::blockchain::{GenesisConfig, ProofOfAuthority};
+    use crate::utils::keys;
+
+    const UPGRADE_HEIGHT: u64 = 2;
+
+    fn authority(key: &SigningKey) -> Engine {
+        Engine::Authority(ProofOfAuthority::new(
+            BlockRules::default(),
+            vec![keys::public_key_hex(key)],
+        ))
+    }
+
+    // Headers of a chain sealed by `signers`, one block each, from `schedule`
+    // with the signer's key
+    async fn sealed_chain(
+        schedule: &ConsensusSchedule,
+        signers: &[&SigningKey],
+    ) -> Vec<BlockHeader<EngineProof>> {
+        let genesis = GenesisConfig::default().spec(schedule).block();
+        let mut headers = vec![genesis.header];
+        for signer in signers {
+            let mut schedule = schedule.clone();
+            schedule.set_signing_key((*signer).clone());
+            let body = BlockBody::default();
+            let timestamp = GENESIS_TIMESTAMP + 60 * headers.len() as i64;
+            let proof = schedule
+                .prove(&headers, timestamp, &body, CancellationToken::new())
+                .await
+                .expect("Signer is the authority of its block");
+            let parent = headers.last().unwrap();
+            let block = Block::new(
+                parent.index + 1,
+                body,
+                timestamp,
+                proof,
+                parent.hash.clone(),
+                schedule.rules().hash,
+            );
+            headers.push(block.header);
+        }
+        headers
+    }
+
+    #[tokio::test]
+    async fn upgrade_takes_over_at_its_height() {
+        let (first, second) = (
+            SigningKey::from_bytes(&[0x11; 32]),
+            SigningKey::from_bytes(&[0x22; 32]),
+        );
+        let schedule = ConsensusSchedule::new(
+            authority(&first),
+            vec![Phase {
+                from_height: UPGRADE_HEIGHT,
+                engine: authority(&second),
+            }],
+        );
+        let headers = sealed_chain(&schedule, &[&first, &second]).await;
+
+        // The block before the upgrade height still follows the first engine
+        // with all history, the one at it the upgrade with only its parent
+        let before = &headers[..UPGRADE_HEIGHT as usize - 1];
+        let (engine, history) = for_next_block(&schedule, before);
+        assert!(std::ptr::eq(engine, &schedule.phases[0].engine));
+        assert_eq!(history.len(), before.len());
+        let (engine, history) = for_next_block(&schedule, &headers[..UPGRADE_HEIGHT as usize]);
+        assert!(std::ptr::eq(engine, &schedule.phases[1].engine));
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].index, UPGRADE_HEIGHT - 1);
+
+        let genesis_hash = &headers[0].hash;
+        assert!(schedule.validate_headers(genesis_hash, &headers).is_ok());
+    }
+
+    #[tokio::test]
+    async fn validate_headers_switches_engine_at_upgrade() {
+        let (first, second) = (
+            SigningKey::from_bytes(&[0x11; 32]),
+            SigningKey::from_bytes(&[0x22; 32]),
+        );
+        // The first authority keeps sealing past the upgrade height
+        let unchanged = ConsensusSchedule::new(authority(&first), Vec::new());
+        let headers = sealed_chain(&unchanged, &[&first, &first, &first]).await;
+        let genesis_hash = &headers[0].hash;
+
+        let upgraded = unchanged.clone().with_upgrades(vec![Phase {
+            from_height: UPGRADE_HEIGHT,
+            engine: authority(&second),
+        }]);
+        assert!(unchanged.validate_headers(genesis_hash, &headers).is_ok());
+        assert!(upgraded
+            .validate_headers(genesis_hash, &headers[..UPGRADE_HEIGHT as usize])
+            .is_ok());
+        match upgraded.validate_headers(genesis_hash, &headers) {
+            Err(ValidationError::InvalidBlock { index, cause }) => {
+                assert_eq!(index, UPGRADE_HEIGHT);
+                assert!(matches!(*cause, ValidationError::WrongValidator { .. }));
+            }
+            other => panic!("Expected the block at the upgrade to fail, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use core::fmt;
+use serde::Serialize;
+
+/// Why a block or chain was refused. Serialized with an `error` tag so peers
+/// and operators get a machine readable reason in HTTP responses.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "error", rename_all = "snake_case")]
+pub enum ValidationError {
+    MissingParent,
+    BadIndex {
+        expected: u64,
+        found: u64,
+    },
+    BadPreviousHash {
+        expected: String,
+        found: String,
+    },
+    HashMismatch {
+        calculated: String,
+        found: String,
+    },
+    MerkleRootMismatch {
+        calculated: String,
+        found: String,
+    },
+    GenesisMismatch {
+        expected: String,
+        found: String,
+    },
+    TimestampTooOld {
+        timestamp: i64,
+        min_timestamp: i64,
+    },
+    TimestampTooFarAhead {
+        timestamp: i64,
+        max_timestamp: i64,
+    },
+    WrongDifficulty {
+        expected: u32,
+        found: u32,
+    },
+    InsufficientWork {
+        hash: String,
+        bits: u32,
+    },
+    WrongValidator {
+        expected: Option<String>,
+        found: String,
+    },
+    BadSignature {
+        validator: String,
+    },
+    BadTransaction {
+        id: String,
+        reason: String,
+    },
+    WrongProofKind {
+        expected: String,
+    },
+    UnknownValidator {
+        validator: String,
+    },
+    InsufficientCommit {
+        index: u64,
+        signatures: usize,
+        quorum: usize,
+    },
+    UnexpectedMessage,
+    DecidedByVote {
+        index: u64,
+    },
+    UnexpectedGenesisData {
+        index: u64,
+    },
+    RevertsFinalizedBlock {
+        index: u64,
+    },
+    CheckpointMismatch {
+        height: u64,
+        expected: String,
+        found: String,
+    },
+    ReorgTooDeep {
+        depth: u64,
+        max_depth: u64,
+    },
+    UnsupportedBlockVersion {
+        version: u32,
+        supported: Vec<u32>,
+    },
+    BlockVersionDowngrade {
+        version: u32,
+        parent_version: u32,
+    },
+    TooManyTransactions {
+        count: usize,
+        max: usize,
+    },
+    BlockTooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// A block inside a chain failed validation.
+    InvalidBlock {
+        index: u64,
+        cause: Box<ValidationError>,
+    },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::MissingParent => write!(f, "parent block is unknown"),
+            ValidationError::BadIndex { expected, found } => {
+                write!(
+                    f,
+                    "index {} does not follow the parent, expected {}",
+                    found, expected
+                )
+            }
+            ValidationError::BadPreviousHash { expected, found } => write!(
+                f,
+                "previous hash {} does not match the parent hash {}",
+                found, expected
+            ),
+            ValidationError::HashMismatch { calculated, found } => write!(
+                f,
+                "hash {} does not match the block contents, which hash to {}",
+                found, calculated
+            ),
+            ValidationError::MerkleRootMismatch { calculated, found } => write!(
+                f,
+                "merkle root {} does not match the block data, which yields {}",
+                found, calculated
+            ),
+            ValidationError::GenesisMismatch { expected, found } => {
+                write!(
+                    f,
+                    "genesis {} is not this network's genesis {}",
+                    found, expected
+                )
+            }
+            ValidationError::TimestampTooOld {
+                timestamp,
+                min_timestamp,
+            } => write!(
+                f,
+                "timestamp {} is not after the median time of recent blocks, must be at least {}",
+                timestamp, min_timestamp
+            ),
+            ValidationError::TimestampTooFarAhead {
+                timestamp,
+                max_timestamp,
+            } => write!(
+                f,
+                "timestamp {} is too far ahead of local time, must be at most {}",
+                timestamp, max_timestamp
+            ),
+            ValidationError::WrongDifficulty { expected, found } => write!(
+                f,
+                "difficulty bits {:08x} differ from the expected {:08x}",
+                found, expected
+            ),
+            ValidationError::InsufficientWork { hash, bits } => {
+                write!(f, "hash {} does not meet target bits {:08x}", hash, bits)
+            }
+            ValidationError::WrongValidator { expected, found } => match expected {
+                Some(expected) => write!(
+                    f,
+                    "block sealed by {} but {} was elected to produce it",
+                    found, expected
+                ),
+                None => write!(f, "block sealed by {} but no validator is elected", found),
+            },
+            ValidationError::BadSignature { validator } => {
+                write!(f, "signature of {} is invalid", validator)
+            }
+            ValidationError::BadTransaction { id, reason } => {
+                write!(f, "transaction {} is invalid: {}", id, reason)
+            }
+            ValidationError::WrongProofKind { expected } => {
+                write!(f, "block does not carry a {} proof", expected)
+            }
+            ValidationError::UnknownValidator { validator } => {
+                write!(f, "{} is not in the validator set", validator)
+            }
+            ValidationError::InsufficientCommit {
+                index,
+                signatures,
+                quorum,
+            } => write!(
+                f,
+                "commit certificate of block #{} has {} valid precommits, {} needed",
+                index, signatures, quorum
+            ),
+            ValidationError::UnexpectedMessage => {
+                write!(f, "this node's consensus engine does not vote on blocks")
+            }
+            ValidationError::DecidedByVote { index } => write!(
+                f,
+                "block #{} is only final with the votes on it, send it as a commit",
+                index
+            ),
+            ValidationError::UnexpectedGenesisData { index } => {
+                write!(f, "block #{} carries genesis data", index)
+            }
+            ValidationError::RevertsFinalizedBlock { index } => {
+                write!(f, "chain reverts finalized block #{}", index)
+            }
+            ValidationError::CheckpointMismatch {
+                height,
+                expected,
+                found,
+            } => write!(
+                f,
+                "block {} at height {} does not match checkpoint {}",
+                found, height, expected
+            ),
+            ValidationError::ReorgTooDeep { depth, max_depth } => write!(
+                f,
+                "reorg replaces {} blocks, at most {} may be replaced",
+                depth, max_depth
+            ),
+            ValidationError::UnsupportedBlockVersion { version, supported } => write!(
+                f,
+                "block version {} is not supported, supported versions are {:?}",
+                version, supported
+            ),
+            ValidationError::BlockVersionDowngrade {
+                version,
+                parent_version,
+            } => write!(
+                f,
+                "block version {} is older than its parent's version {}",
+                version, parent_version
+            ),
+            ValidationError::TooManyTransactions { count, max } => write!(
+                f,
+                "block carries {} transactions, at most {} are allowed",
+                count, max
+            ),
+            ValidationError::BlockTooLarge { size, max } => write!(
+                f,
+                "block body takes {} bytes, at most {} are allowed",
+                size, max
+            ),
+            ValidationError::InvalidBlock { index, cause } => {
+                write!(f, "block #{} is invalid: {}", index, cause)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
@@ -1,5 +1,5 @@
 use crate::api::{client, server};
-use crate::blockchain::{Block, Chain, Consensus, MessageQueue, MiningCommand};
+use crate::blockchain::{Block, Chain, Consensus, MessageQueue, MessageTransaction, MiningCommand};
 use actix_web::rt::spawn;
 use actix_web::{web, HttpResponse, Responder};
 use askama::Template;
@@ -15,6 +15,7 @@ struct DashboardTemplate<'a, P: std::fmt::Display> {
     poll_interval_s: u64,
 }
 
+#[allow(dead_code)]
 #[derive(Template)]
 #[template(path = "components/block.html")]
 struct BlockTemplate<'a, P: std::fmt::Display> {
@@ -82,12 +83,13 @@ pub async fn handle_message_from_submit<C: Consensus>(
     form: web::Form<HashMap<String, String>>,
 ) -> impl Responder {
     let message = form.get("message").cloned().unwrap_or_default();
-    match message_queue.submit_message(message).await {
+    let transaction = MessageTransaction::signed(&app_state.submission_key, message);
+    match message_queue.submit_message(transaction).await {
         Ok(_) => {
             let _ = app_state.mining_tx.try_send(MiningCommand::StartMining);
             HttpResponse::Ok().body("Message submitted. Starting to mine.")
         }
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => HttpResponse::BadRequest().body(e),
     }
 }
 
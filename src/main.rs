@@ -2,52 +2,412 @@ mod api;
 mod blockchain;
 mod frontend;
 mod utils;
+mod wallet;
 
 use api::server::run_server;
-use blockchain::{Chain, ProofOfWork};
-use clap::Parser;
+use blockchain::{
+    BftFinality, Chain, ConsensusSchedule, Engine, GenesisConfig, MemoryHardProofOfWork, Phase,
+    ProofOfAuthority, ProofOfStake, ProofOfWork, Retargeting,
+};
+use clap::{Parser, Subcommand};
+use ed25519_dalek::SigningKey;
+use std::collections::BTreeMap;
+use utils::keys;
+use wallet::Keystore;
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about=None)]
+#[command(version, about, long_about=None, subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(short, long)]
-    port: u16,
+    /// Port the node listens on, not needed by the wallet commands
+    #[arg(short, long, required = true)]
+    port: Option<u16>,
 
     #[arg(short = 'f', long, num_args = 0..=1, default_missing_value = "")]
     chain_file: Option<String>,
 
-    #[arg(short, long, default_value = "pow")]
-    consensus: String,
+    /// Genesis config JSON shared by all nodes of the network
+    #[arg(short, long)]
+    genesis: Option<String>,
+
+    /// Consensus engine: pow, mhpow (memory-hard proof of work), pos, poa or
+    /// bft (validators vote on final blocks).
+    /// Defaults to the engine of the chain file or genesis config, then pow
+    #[arg(short, long)]
+    consensus: Option<String>,
+
+    /// Leading zero bits required in proof of work hashes until the first
+    /// retarget, defaults to 16 (pow) or 8 (mhpow)
+    #[arg(short, long)]
+    difficulty: Option<u32>,
+
+    /// Block interval the proof of work difficulty is retargeted towards (pow, mhpow)
+    #[arg(long, default_value_t = 10)]
+    target_block_time: i64,
+
+    /// Number of blocks between difficulty retargets (pow, mhpow)
+    #[arg(long, default_value_t = 10)]
+    retarget_window: usize,
+
+    /// Worker threads searching for a nonce, defaults to all cores (pow, mhpow)
+    #[arg(long)]
+    mining_threads: Option<usize>,
+
+    /// Memory each proof of work hash needs in KiB, rounded down to a power of two (mhpow)
+    #[arg(long, default_value_t = 1024)]
+    memory_cost: u32,
+
+    /// Hex encoded ed25519 secret key used to sign blocks (pos, poa, bft)
+    #[arg(long)]
+    validator_key: Option<String>,
+
+    /// Initial stake as <public key hex>=<amount> (pos), may be repeated.
+    /// Required unless the genesis config has allocations
+    #[arg(long = "stake")]
+    stakes: Vec<String>,
+
+    /// Public key hex of a block signing authority (poa), may be repeated.
+    /// Authorities take turns in the order given. Required unless the genesis
+    /// config defines the consensus
+    #[arg(long = "authority")]
+    authorities: Vec<String>,
+
+    /// Public key hex of a voting validator (bft), may be repeated.
+    /// Validators take turns proposing in the order given. Required unless the
+    /// genesis config defines the consensus
+    #[arg(long = "validator")]
+    validators: Vec<String>,
+
+    /// Seconds a voting round may take before the next validator proposes (bft)
+    #[arg(long, default_value_t = 10)]
+    round_timeout: i64,
+
+    /// Block hash the chain must have at a height, as <height>=<hash>, in
+    /// addition to the genesis config checkpoints. May be repeated
+    #[arg(long = "checkpoint")]
+    checkpoints: Vec<String>,
 
-    #[arg(short, long, default_value_t = 4)]
-    difficulty: u64,
+    /// Most blocks a sync with peers may replace, overrides the genesis config
+    #[arg(long)]
+    max_reorg_depth: Option<u64>,
+
+    /// Address of the wallet key signing messages submitted to this node,
+    /// defaults to the first key in the keystore
+    #[arg(long)]
+    wallet_address: Option<String>,
+
+    /// Keystore file of the wallet keys, defaults to next to the chain file,
+    /// or wallet.keystore.json for wallet commands given no port or chain file
+    #[arg(long)]
+    keystore: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[tokio::main]
-async fn main() -> std::io::Result<()> {
-    let args = Args::parse();
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage the wallet keys kept encrypted in the keystore
+    #[command(subcommand)]
+    Wallet(WalletCommand),
+}
+
+#[derive(Subcommand, Debug)]
+enum WalletCommand {
+    /// Generate a key pair and store it under a new password
+    Create {
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// List the names, addresses and public keys in the keystore
+    List,
+    /// Print the secret key hex of an address, usable as --validator-key
+    Export { address: String },
+}
+
+fn run_wallet_command(command: &WalletCommand, keystore_path: &str) {
+    let mut keystore = Keystore::load(keystore_path).unwrap_or_else(|e| {
+        exit_with_error(&format!("Failed to load keystore {}: {}", keystore_path, e))
+    });
+    match command {
+        WalletCommand::Create { name } => {
+            let password = read_password("Password for the new key: ");
+            if std::env::var(wallet::PASSWORD_ENV).is_err()
+                && read_password("Repeat the password: ") != password
+            {
+                exit_with_error("Passwords do not match");
+            }
+            let key = keystore
+                .create_key(name.clone(), &password)
+                .unwrap_or_else(|e| exit_with_error(&format!("Failed to create key: {}", e)));
+            println!(
+                "Created key {} with address {} (public key {})",
+                key.name, key.address, key.public_key
+            );
+            keystore.save().unwrap_or_else(|e| {
+                exit_with_error(&format!("Failed to save keystore {}: {}", keystore_path, e))
+            });
+        }
+        WalletCommand::List => {
+            if keystore.keys().is_empty() {
+                println!("No keys in {}", keystore.path());
+            }
+            for key in keystore.keys() {
+                println!("{}\t{}\t{}", key.name, key.address, key.public_key);
+            }
+        }
+        WalletCommand::Export { address } => {
+            let key = unlock_wallet_key(&keystore, address);
+            println!("{}", hex::encode(key.to_bytes()));
+        }
+    }
+}
+
+fn read_password(prompt: &str) -> String {
+    wallet::read_password(prompt)
+        .unwrap_or_else(|e| exit_with_error(&format!("Failed to read password: {}", e)))
+}
 
-    let chain_file = match args.chain_file {
-        Some(file) if !file.is_empty() => file,
-        _ => format!("chain_{}.json", args.port),
+fn unlock_wallet_key(keystore: &Keystore, address: &str) -> SigningKey {
+    let key = keystore
+        .find(address)
+        .unwrap_or_else(|e| exit_with_error(&format!("Failed to find wallet key: {}", e)));
+    let password = read_password(&format!("Password for {}: ", key.address));
+    key.unlock(&password).unwrap_or_else(|e| {
+        exit_with_error(&format!(
+            "Failed to unlock wallet key {}: {}",
+            key.address, e
+        ))
+    })
+}
+
+// Reports a mistake of the user, like a wrong password, without a panic
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1)
+}
+
+// Key signing the messages submitted to this node: a wallet key if the
+// keystore has one, a throwaway key otherwise
+fn load_submission_key(keystore_path: &str, address: &Option<String>) -> SigningKey {
+    let keystore = Keystore::load(keystore_path).unwrap_or_else(|e| {
+        exit_with_error(&format!("Failed to load keystore {}: {}", keystore_path, e))
+    });
+    let address = match address {
+        Some(address) => address.clone(),
+        None => match keystore.keys().first() {
+            Some(key) => key.address.clone(),
+            None => {
+                let key = keys::generate_signing_key();
+                println!(
+                    "No wallet keys in {}, signing submitted messages with throwaway key {} (create one with `wallet create`)",
+                    keystore_path,
+                    keys::public_key_hex(&key)
+                );
+                return key;
+            }
+        },
     };
+    let key = unlock_wallet_key(&keystore, &address);
+    println!("Signing submitted messages with wallet key {}", address);
+    key
+}
+
+fn load_signing_key(validator_key: &Option<String>) -> SigningKey {
+    match validator_key {
+        Some(secret) => keys::signing_key_from_hex(secret)
+            .unwrap_or_else(|e| panic!("Invalid validator key: {}", e)),
+        None => {
+            let key = keys::generate_signing_key();
+            println!(
+                "Generated validator key {} (pass --validator-key to reuse it)",
+                hex::encode(key.to_bytes())
+            );
+            key
+        }
+    }
+}
 
-    let chain = match args.consensus.as_str() {
+fn parse_stakes(stakes: &[String]) -> BTreeMap<String, u64> {
+    stakes
+        .iter()
+        .map(|entry| {
+            let (validator, amount) = entry
+                .split_once('=')
+                .unwrap_or_else(|| panic!("Stake {} must be <public key>=<amount>", entry));
+            let amount = amount
+                .parse()
+                .unwrap_or_else(|_| panic!("Invalid stake amount in {}", entry));
+            (validator.to_string(), amount)
+        })
+        .collect()
+}
+
+fn parse_checkpoints(checkpoints: &[String]) -> BTreeMap<u64, String> {
+    checkpoints
+        .iter()
+        .map(|entry| {
+            let (height, hash) = entry
+                .split_once('=')
+                .unwrap_or_else(|| panic!("Checkpoint {} must be <height>=<hash>", entry));
+            let height = height
+                .parse()
+                .unwrap_or_else(|_| panic!("Invalid checkpoint height in {}", entry));
+            (height, hash.to_string())
+        })
+        .collect()
+}
+
+// Engine for a new chain when the genesis config does not define one
+fn engine_from_args(args: &Args, genesis: &GenesisConfig) -> Engine {
+    let consensus = args.consensus.as_deref().unwrap_or("pow");
+    match consensus {
         "pow" => {
-            Chain::load_or_create(&chain_file, ProofOfWork::new(args.difficulty as usize)).await
+            let retargeting = Retargeting::new(
+                args.difficulty.unwrap_or(16),
+                args.target_block_time,
+                args.retarget_window,
+            );
+            Engine::Work(ProofOfWork::new(genesis.rules.clone(), retargeting))
+        }
+        "mhpow" => {
+            let retargeting = Retargeting::new(
+                args.difficulty.unwrap_or(8),
+                args.target_block_time,
+                args.retarget_window,
+            );
+            Engine::MemoryHardWork(MemoryHardProofOfWork::new(
+                genesis.rules.clone(),
+                retargeting,
+                args.memory_cost,
+            ))
         }
         "pos" => {
-            unimplemented!("Proof of Stake not implemented.")
+            let stakes = if args.stakes.is_empty() {
+                genesis.allocations.clone()
+            } else {
+                parse_stakes(&args.stakes)
+            };
+            let consensus = ProofOfStake::new(genesis.rules.clone(), stakes);
+            if !consensus.has_stakes() {
+                exit_with_error(
+                    "Proof of stake needs --stake or a genesis config with allocations",
+                );
+            }
+            Engine::Stake(consensus)
+        }
+        "poa" => {
+            if args.authorities.is_empty() {
+                exit_with_error("Proof of authority needs --authority or a genesis config");
+            }
+            Engine::Authority(ProofOfAuthority::new(
+                genesis.rules.clone(),
+                args.authorities.clone(),
+            ))
         }
-        _ => panic!("Unsupported Consensus type {}", args.consensus),
+        "bft" => {
+            if args.validators.is_empty() {
+                exit_with_error("BFT finality needs --validator or a genesis config");
+            }
+            Engine::Finality(BftFinality::new(
+                genesis.rules.clone(),
+                args.validators.clone(),
+                args.round_timeout,
+            ))
+        }
+        _ => panic!("Unsupported Consensus type {}", consensus),
+    }
+}
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let args = Args::parse();
+
+    let chain_file = match (&args.chain_file, args.port) {
+        (Some(file), _) if !file.is_empty() => Some(file.clone()),
+        (_, Some(port)) => Some(format!("chain_{}.json", port)),
+        _ => None,
+    };
+    let keystore_path = match (&args.keystore, &chain_file) {
+        (Some(path), _) => path.clone(),
+        (None, Some(chain_file)) => wallet::keystore_path(chain_file),
+        (None, None) => wallet::DEFAULT_KEYSTORE.to_string(),
     };
 
+    if let Some(Command::Wallet(command)) = &args.command {
+        run_wallet_command(command, &keystore_path);
+        return Ok(());
+    }
+    // Only the wallet commands go without a port
+    let port = args.port.expect("--port is required to run a node");
+    let chain_file = chain_file.expect("A node with a port has a chain file");
+
+    let mut genesis = match &args.genesis {
+        Some(path) => GenesisConfig::load(path)
+            .unwrap_or_else(|e| panic!("Failed to load genesis config {}: {}", path, e)),
+        None => GenesisConfig::default(),
+    };
     println!(
-        "Starting node on port {} with consensus {} (difficulty {}) (chain file: {})",
-        args.port, args.consensus, args.difficulty, chain_file
+        "Using genesis of chain {} (hash {})",
+        genesis.chain_id, genesis.rules.hash
     );
-    let chain = chain;
+    genesis
+        .finality
+        .checkpoints
+        .extend(parse_checkpoints(&args.checkpoints));
+    if args.max_reorg_depth.is_some() {
+        genesis.finality.max_reorg_depth = args.max_reorg_depth;
+    }
+
+    let engine = match &genesis.consensus {
+        Some(engine) => engine.clone().with_rules(genesis.rules.clone()),
+        None => engine_from_args(&args, &genesis),
+    };
+
+    let upgrades: Vec<Phase> = genesis
+        .upgrades
+        .iter()
+        .map(|phase| Phase {
+            from_height: phase.from_height,
+            engine: phase.engine.clone().with_rules(genesis.rules.clone()),
+        })
+        .collect();
+    let schedule = ConsensusSchedule::new(engine, upgrades.clone());
+
+    let mut chain = Chain::load_or_create(&chain_file, schedule, &genesis)
+        .unwrap_or_else(|e| exit_with_error(&e));
+    if !upgrades.is_empty() {
+        chain.consensus = chain.consensus.with_upgrades(upgrades);
+    }
+    if let Some(consensus) = &args.consensus {
+        let initial = chain.consensus.engine_at(0).name();
+        if consensus != initial {
+            panic!(
+                "Chain {} uses consensus {}, not {}",
+                chain_file, initial, consensus
+            );
+        }
+    }
+    if let Some(threads) = args.mining_threads {
+        chain.consensus.set_mining_threads(threads);
+    }
+
+    if chain.consensus.is_signed() {
+        let signing_key = load_signing_key(&args.validator_key);
+        let validator = keys::public_key_hex(&signing_key);
+        chain.consensus.set_signing_key(signing_key);
+        println!(
+            "Starting node on port {} with consensus {} as validator {} (chain file: {})",
+            port, chain.consensus, validator, chain_file
+        );
+    } else {
+        println!(
+            "Starting node on port {} with consensus {} (chain file: {})",
+            port, chain.consensus, chain_file
+        );
+    }
+
+    let submission_key = load_submission_key(&keystore_path, &args.wallet_address);
 
-    let address = format!("127.0.0.1:{}", args.port);
-    run_server(chain, &address, chain_file).await
+    let address = format!("127.0.0.1:{}", port);
+    run_server(chain, &address, chain_file, submission_key).await
 }
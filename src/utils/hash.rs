@@ -1,29 +1,100 @@
+use super::encoding::{Encode, ENCODING_VERSION};
+use core::fmt;
 use hex;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use sha3::Keccak256;
 
-pub fn hash<T: Serialize>(
+/// Hash function a network uses for block hashes and proof of work, fixed in
+/// its genesis config.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    DoubleSha256,
+    Blake3,
+    Keccak256,
+}
+
+impl HashAlgorithm {
+    pub fn digest(&self, bytes: &[u8]) -> [u8; 32] {
+        match self {
+            HashAlgorithm::Sha256 => Sha256::digest(bytes).into(),
+            HashAlgorithm::DoubleSha256 => Sha256::digest(Sha256::digest(bytes)).into(),
+            HashAlgorithm::Blake3 => blake3::hash(bytes).into(),
+            HashAlgorithm::Keccak256 => Keccak256::digest(bytes).into(),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::DoubleSha256 => "double_sha256",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Keccak256 => "keccak256",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+pub fn hash<T: Encode>(
+    algorithm: HashAlgorithm,
+    version: u32,
     index: u64,
     timestamp: i64,
-    data: &str,
+    merkle_root: &str,
     previous_hash: &str,
     proof: &T,
 ) -> String {
-    let mut hasher = Sha256::new();
-
-    hasher.update(index.to_be_bytes());
-    hasher.update(b"|");
-    hasher.update(timestamp.to_be_bytes());
-    hasher.update(b"|");
-    hasher.update(data.as_bytes());
-    hasher.update(b"|");
-    hasher.update(previous_hash.as_bytes());
-    hasher.update(b"|");
-
-    let proof_bytes = serde_json::to_vec(proof).unwrap_or_else(|_| vec![]);
-    hasher.update(&proof_bytes);
+    hex::encode(hash_bytes(
+        algorithm,
+        version,
+        index,
+        timestamp,
+        merkle_root,
+        previous_hash,
+        proof,
+    ))
+}
 
-    let result = hasher.finalize();
+pub fn hash_bytes<T: Encode>(
+    algorithm: HashAlgorithm,
+    version: u32,
+    index: u64,
+    timestamp: i64,
+    merkle_root: &str,
+    previous_hash: &str,
+    proof: &T,
+) -> [u8; 32] {
+    algorithm.digest(&header_preimage(
+        version,
+        index,
+        timestamp,
+        merkle_root,
+        previous_hash,
+        proof,
+    ))
+}
 
-    hex::encode(result)
+/// Encoding of a block header up to, and excluding, its hash. This is what
+/// the block hash is computed over.
+pub fn header_preimage<T: Encode>(
+    version: u32,
+    index: u64,
+    timestamp: i64,
+    merkle_root: &str,
+    previous_hash: &str,
+    proof: &T,
+) -> Vec<u8> {
+    let mut preimage = vec![ENCODING_VERSION];
+    version.encode_to(&mut preimage);
+    index.encode_to(&mut preimage);
+    timestamp.encode_to(&mut preimage);
+    merkle_root.encode_to(&mut preimage);
+    previous_hash.encode_to(&mut preimage);
+    proof.encode_to(&mut preimage);
+    preimage
 }
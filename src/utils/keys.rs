@@ -0,0 +1,41 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+
+pub fn generate_signing_key() -> SigningKey {
+    SigningKey::generate(&mut OsRng)
+}
+
+pub fn signing_key_from_hex(secret_hex: &str) -> Result<SigningKey, String> {
+    let bytes = hex::decode(secret_hex).map_err(|e| format!("Invalid key hex: {}", e))?;
+    let secret: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| "Secret key must be 32 bytes".to_string())?;
+    Ok(SigningKey::from_bytes(&secret))
+}
+
+pub fn public_key_hex(key: &SigningKey) -> String {
+    hex::encode(key.verifying_key().to_bytes())
+}
+
+pub fn sign(key: &SigningKey, message: &[u8]) -> String {
+    hex::encode(key.sign(message).to_bytes())
+}
+
+pub fn verify(public_key_hex: &str, message: &[u8], signature_hex: &str) -> bool {
+    let Ok(public_bytes) = hex::decode(public_key_hex) else {
+        return false;
+    };
+    let Ok(public_bytes) = <[u8; 32]>::try_from(public_bytes) else {
+        return false;
+    };
+    let Ok(public_key) = VerifyingKey::from_bytes(&public_bytes) else {
+        return false;
+    };
+    let Ok(signature_bytes) = hex::decode(signature_hex) else {
+        return false;
+    };
+    let Ok(signature) = Signature::from_slice(&signature_bytes) else {
+        return false;
+    };
+    public_key.verify(message, &signature).is_ok()
+}
@@ -0,0 +1,172 @@
+//! Binary Merkle trees over 32 byte hashes. Leaves and inner nodes are
+//! hashed with different prefixes so an inner node cannot pass for a leaf,
+//! and a node without a sibling moves up a level unchanged.
+
+use super::HashAlgorithm;
+use serde::Serialize;
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+/// Side of the sibling hash at one step of an inclusion proof.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProofStep {
+    pub hash: String,
+    pub side: Side,
+}
+
+pub fn leaf_hash(algorithm: HashAlgorithm, bytes: &[u8]) -> [u8; 32] {
+    let mut preimage = Vec::with_capacity(bytes.len() + 1);
+    preimage.push(LEAF_PREFIX);
+    preimage.extend_from_slice(bytes);
+    algorithm.digest(&preimage)
+}
+
+fn node_hash(algorithm: HashAlgorithm, left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut preimage = Vec::with_capacity(65);
+    preimage.push(NODE_PREFIX);
+    preimage.extend_from_slice(left);
+    preimage.extend_from_slice(right);
+    algorithm.digest(&preimage)
+}
+
+// Hashes each pair of `level` into the level above
+fn parent_level(algorithm: HashAlgorithm, level: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => node_hash(algorithm, left, right),
+            [single] => *single,
+            _ => unreachable!("chunks of two"),
+        })
+        .collect()
+}
+
+/// Root over `leaves`, the hash of an empty leaf when there are none.
+pub fn root(algorithm: HashAlgorithm, leaves: &[[u8; 32]]) -> [u8; 32] {
+    if leaves.is_empty() {
+        return leaf_hash(algorithm, &[]);
+    }
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = parent_level(algorithm, &level);
+    }
+    level[0]
+}
+
+/// Sibling hashes from the leaf at `index` up to the root, `None` if there is
+/// no such leaf.
+pub fn proof(
+    algorithm: HashAlgorithm,
+    leaves: &[[u8; 32]],
+    index: usize,
+) -> Option<Vec<ProofStep>> {
+    if index >= leaves.len() {
+        return None;
+    }
+    let mut steps = Vec::new();
+    let mut level = leaves.to_vec();
+    let mut position = index;
+    while level.len() > 1 {
+        let sibling = position ^ 1;
+        if let Some(hash) = level.get(sibling) {
+            steps.push(ProofStep {
+                hash: hex::encode(hash),
+                side: if sibling < position {
+                    Side::Left
+                } else {
+                    Side::Right
+                },
+            });
+        }
+        level = parent_level(algorithm, &level);
+        position /= 2;
+    }
+    Some(steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALGORITHM: HashAlgorithm = HashAlgorithm::Sha256;
+
+    fn leaves(count: u8) -> Vec<[u8; 32]> {
+        (0..count).map(|i| leaf_hash(ALGORITHM, &[i])).collect()
+    }
+
+    // Folds `leaf` with each step of `path`, as a client checking a proof would
+    fn fold(leaf: [u8; 32], path: &[ProofStep]) -> [u8; 32] {
+        path.iter().fold(leaf, |hash, step| {
+            let sibling: [u8; 32] = hex::decode(&step.hash).unwrap().try_into().unwrap();
+            match step.side {
+                Side::Left => node_hash(ALGORITHM, &sibling, &hash),
+                Side::Right => node_hash(ALGORITHM, &hash, &sibling),
+            }
+        })
+    }
+
+    #[test]
+    fn root_of_no_leaves_is_the_empty_leaf() {
+        assert_eq!(root(ALGORITHM, &[]), leaf_hash(ALGORITHM, &[]));
+    }
+
+    #[test]
+    fn root_of_one_leaf_is_the_leaf() {
+        let leaves = leaves(1);
+        assert_eq!(root(ALGORITHM, &leaves), leaves[0]);
+    }
+
+    #[test]
+    fn root_moves_a_lone_node_up_unchanged() {
+        let l = leaves(3);
+        let expected = node_hash(ALGORITHM, &node_hash(ALGORITHM, &l[0], &l[1]), &l[2]);
+        assert_eq!(root(ALGORITHM, &l), expected);
+    }
+
+    #[test]
+    fn leaves_and_nodes_are_domain_separated() {
+        let l = leaves(2);
+        let mut concatenated = l[0].to_vec();
+        concatenated.extend_from_slice(&l[1]);
+        assert_ne!(root(ALGORITHM, &l), leaf_hash(ALGORITHM, &concatenated));
+    }
+
+    #[test]
+    fn proofs_lead_to_the_root() {
+        for count in 1..=9 {
+            let l = leaves(count);
+            let root = root(ALGORITHM, &l);
+            for (index, leaf) in l.iter().enumerate() {
+                let path = proof(ALGORITHM, &l, index).unwrap();
+                assert_eq!(fold(*leaf, &path), root, "leaf {} of {}", index, count);
+            }
+        }
+    }
+
+    #[test]
+    fn proof_sides_follow_the_position() {
+        let l = leaves(3);
+        let path = proof(ALGORITHM, &l, 2).unwrap();
+        // The lone third leaf skips the first level
+        assert_eq!(path.len(), 1);
+        assert_eq!(path[0].side, Side::Left);
+        assert_eq!(
+            path[0].hash,
+            hex::encode(node_hash(ALGORITHM, &l[0], &l[1]))
+        );
+    }
+
+    #[test]
+    fn proof_of_a_missing_leaf_is_none() {
+        assert!(proof(ALGORITHM, &leaves(2), 2).is_none());
+        assert!(proof(ALGORITHM, &[], 0).is_none());
+    }
+}
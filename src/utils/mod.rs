@@ -1,3 +1,6 @@
+pub mod encoding;
 pub mod hash;
+pub mod keys;
+pub mod merkle;
 
-pub use hash::hash;
+pub use hash::{hash, hash_bytes, header_preimage, HashAlgorithm};
@@ -0,0 +1,466 @@
+//! Keystore file holding wallet keys encrypted under a password.
+//!
+//! Each secret key is encrypted on its own: scrypt stretches the password and
+//! a random salt into a cipher key and a MAC key, XSalsa20 encrypts the secret
+//! and HMAC-SHA256 over the nonce and ciphertext detects a wrong password or a
+//! tampered file before anything is decrypted.
+
+use super::address;
+use crate::utils::keys;
+use chrono::Utc;
+use core::fmt;
+use ed25519_dalek::SigningKey;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use salsa20::cipher::{KeyIvInit, StreamCipher};
+use salsa20::XSalsa20;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+
+/// Layout of the keystore file.
+pub const KEYSTORE_VERSION: u32 = 1;
+
+// scrypt cost of new keys, about 32 MiB of memory per unlock
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+// Most scrypt cost a keystore file may ask for, about 1 GiB of memory, so a
+// crafted file cannot exhaust memory or stall the unlock
+const MAX_SCRYPT_LOG_N: u8 = 20;
+const MAX_SCRYPT_R: u32 = 8;
+const MAX_SCRYPT_P: u32 = 4;
+
+const SALT_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+const KEY_LEN: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug)]
+pub enum KeystoreError {
+    Io(io::Error),
+    Format(serde_json::Error),
+    UnsupportedVersion { version: u32 },
+    InvalidKdfParams,
+    Corrupt,
+    WrongPassword,
+    UnknownKey { address: String },
+    DuplicateName { name: String },
+}
+
+impl fmt::Display for KeystoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeystoreError::Io(e) => write!(f, "keystore file error: {}", e),
+            KeystoreError::Format(e) => write!(f, "keystore is not valid: {}", e),
+            KeystoreError::UnsupportedVersion { version } => write!(
+                f,
+                "keystore version {} is not supported, expected {}",
+                version, KEYSTORE_VERSION
+            ),
+            KeystoreError::InvalidKdfParams => write!(f, "scrypt parameters are out of range"),
+            KeystoreError::Corrupt => write!(f, "stored key is corrupt"),
+            KeystoreError::WrongPassword => write!(f, "wrong password"),
+            KeystoreError::UnknownKey { address } => {
+                write!(f, "no key with address {} in the keystore", address)
+            }
+            KeystoreError::DuplicateName { name } => {
+                write!(f, "a key named {} is already in the keystore", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for KeystoreError {}
+
+impl From<io::Error> for KeystoreError {
+    fn from(e: io::Error) -> Self {
+        KeystoreError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for KeystoreError {
+    fn from(e: serde_json::Error) -> Self {
+        KeystoreError::Format(e)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Keystore {
+    #[serde(skip)]
+    path: String,
+    version: u32,
+    keys: Vec<StoredKey>,
+}
+
+/// A wallet key, its secret encrypted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredKey {
+    pub name: String,
+    pub address: String,
+    pub public_key: String,
+    pub created_at: i64,
+    crypto: EncryptedSecret,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EncryptedSecret {
+    kdf: KdfParams,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+    mac: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KdfParams {
+    log_n: u8,
+    r: u32,
+    p: u32,
+}
+
+impl Keystore {
+    /// Keystore in `path`, empty if the file does not exist yet.
+    pub fn load(path: &str) -> Result<Self, KeystoreError> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(Keystore {
+                    path: path.to_string(),
+                    version: KEYSTORE_VERSION,
+                    keys: Vec::new(),
+                })
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut keystore: Keystore = serde_json::from_reader(file)?;
+        if keystore.version != KEYSTORE_VERSION {
+            return Err(KeystoreError::UnsupportedVersion {
+                version: keystore.version,
+            });
+        }
+        keystore.path = path.to_string();
+        Ok(keystore)
+    }
+
+    /// Writes the keystore, readable and writable by the owner only. It goes
+    /// to a temporary file next to the keystore first, which then replaces it
+    /// at once, so a crash never leaves a half written keystore behind.
+    pub fn save(&self) -> Result<(), KeystoreError> {
+        let temp_path = format!("{}.{}.tmp", self.path, uuid::Uuid::new_v4());
+        let saved = self
+            .write_new(&temp_path)
+            .and_then(|()| Ok(fs::rename(&temp_path, &self.path)?));
+        if saved.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        saved
+    }
+
+    // Writes the keystore to `path`, which must not exist yet, and flushes it
+    // to disk
+    fn write_new(&self, path: &str) -> Result<(), KeystoreError> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn keys(&self) -> &[StoredKey] {
+        &self.keys
+    }
+
+    pub fn find(&self, address: &str) -> Result<&StoredKey, KeystoreError> {
+        self.keys
+            .iter()
+            .find(|key| key.address == address)
+            .ok_or_else(|| KeystoreError::UnknownKey {
+                address: address.to_string(),
+            })
+    }
+
+    /// Generates a key pair and adds it encrypted under `password`. The
+    /// keystore still has to be saved.
+    pub fn create_key(
+        &mut self,
+        name: Option<String>,
+        password: &str,
+    ) -> Result<&StoredKey, KeystoreError> {
+        let name = name.unwrap_or_else(|| format!("key-{}", self.keys.len() + 1));
+        if self.keys.iter().any(|key| key.name == name) {
+            return Err(KeystoreError::DuplicateName { name });
+        }
+
+        let signing_key = keys::generate_signing_key();
+        self.keys.push(StoredKey {
+            name,
+            address: address(&signing_key.verifying_key()),
+            public_key: keys::public_key_hex(&signing_key),
+            created_at: Utc::now().timestamp(),
+            crypto: EncryptedSecret::seal(&signing_key, password)?,
+        });
+        Ok(self.keys.last().expect("A key was just added"))
+    }
+}
+
+impl StoredKey {
+    /// Decrypts the secret key with `password`.
+    pub fn unlock(&self, password: &str) -> Result<SigningKey, KeystoreError> {
+        let secret = self.crypto.open(password)?;
+        let signing_key = SigningKey::from_bytes(&secret);
+        if keys::public_key_hex(&signing_key) != self.public_key {
+            return Err(KeystoreError::Corrupt);
+        }
+        Ok(signing_key)
+    }
+}
+
+impl EncryptedSecret {
+    fn seal(signing_key: &SigningKey, password: &str) -> Result<Self, KeystoreError> {
+        Self::seal_with(KdfParams::NEW_KEYS, signing_key, password)
+    }
+
+    // Sealed at the scrypt cost of `kdf`, which is stored alongside for `open`
+    fn seal_with(
+        kdf: KdfParams,
+        signing_key: &SigningKey,
+        password: &str,
+    ) -> Result<Self, KeystoreError> {
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut nonce);
+
+        let (cipher_key, mac_key) = kdf.derive(password, &salt)?;
+        let mut ciphertext = signing_key.to_bytes();
+        XSalsa20::new(&cipher_key.into(), &nonce.into()).apply_keystream(&mut ciphertext);
+        let mac = authenticate(&mac_key, &nonce, &ciphertext);
+
+        Ok(EncryptedSecret {
+            kdf,
+            salt: hex::encode(salt),
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+            mac: hex::encode(mac),
+        })
+    }
+
+    fn open(&self, password: &str) -> Result<[u8; KEY_LEN], KeystoreError> {
+        let salt = decode_hex(&self.salt)?;
+        let nonce: [u8; NONCE_LEN] = decode_hex(&self.nonce)?
+            .try_into()
+            .map_err(|_| KeystoreError::Corrupt)?;
+        let mut secret: [u8; KEY_LEN] = decode_hex(&self.ciphertext)?
+            .try_into()
+            .map_err(|_| KeystoreError::Corrupt)?;
+        let mac = decode_hex(&self.mac)?;
+
+        let (cipher_key, mac_key) = self.kdf.derive(password, &salt)?;
+        let mut verifier =
+            HmacSha256::new_from_slice(&mac_key).expect("HMAC accepts keys of any length");
+        verifier.update(&nonce);
+        verifier.update(&secret);
+        if verifier.verify_slice(&mac).is_err() {
+            return Err(KeystoreError::WrongPassword);
+        }
+
+        XSalsa20::new(&cipher_key.into(), &nonce.into()).apply_keystream(&mut secret);
+        Ok(secret)
+    }
+}
+
+impl KdfParams {
+    const NEW_KEYS: KdfParams = KdfParams {
+        log_n: SCRYPT_LOG_N,
+        r: SCRYPT_R,
+        p: SCRYPT_P,
+    };
+
+    // Cipher key and MAC key stretched from `password`
+    fn derive(
+        &self,
+        password: &str,
+        salt: &[u8],
+    ) -> Result<([u8; KEY_LEN], [u8; KEY_LEN]), KeystoreError> {
+        if !(1..=MAX_SCRYPT_LOG_N).contains(&self.log_n)
+            || !(1..=MAX_SCRYPT_R).contains(&self.r)
+            || !(1..=MAX_SCRYPT_P).contains(&self.p)
+        {
+            return Err(KeystoreError::InvalidKdfParams);
+        }
+        let params = scrypt::Params::new(self.log_n, self.r, self.p, 2 * KEY_LEN)
+            .map_err(|_| KeystoreError::InvalidKdfParams)?;
+        let mut derived = [0u8; 2 * KEY_LEN];
+        scrypt::scrypt(password.as_bytes(), salt, &params, &mut derived)
+            .expect("Output length matches the scrypt params");
+
+        let mut cipher_key = [0u8; KEY_LEN];
+        let mut mac_key = [0u8; KEY_LEN];
+        cipher_key.copy_from_slice(&derived[..KEY_LEN]);
+        mac_key.copy_from_slice(&derived[KEY_LEN..]);
+        Ok((cipher_key, mac_key))
+    }
+}
+
+fn decode_hex(field: &str) -> Result<Vec<u8>, KeystoreError> {
+    hex::decode(field).map_err(|_| KeystoreError::Corrupt)
+}
+
+fn authenticate(mac_key: &[u8], nonce: &[u8], ciphertext: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(mac_key).expect("HMAC accepts keys of any length");
+    mac.update(nonce);
+    mac.update(ciphertext);
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Cheap enough for tests, new keys use `KdfParams::NEW_KEYS`
+    const TEST_KDF: KdfParams = KdfParams {
+        log_n: 4,
+        r: 8,
+        p: 1,
+    };
+
+    fn sealed(password: &str) -> (SigningKey, EncryptedSecret) {
+        let signing_key = keys::generate_signing_key();
+        let sealed = EncryptedSecret::seal_with(TEST_KDF, &signing_key, password).unwrap();
+        (signing_key, sealed)
+    }
+
+    #[test]
+    fn open_returns_the_sealed_secret() {
+        let (signing_key, sealed) = sealed("correct horse");
+        assert_eq!(
+            sealed.open("correct horse").unwrap(),
+            signing_key.to_bytes()
+        );
+        assert_ne!(sealed.ciphertext, hex::encode(signing_key.to_bytes()));
+    }
+
+    #[test]
+    fn open_rejects_a_wrong_password() {
+        let (_, sealed) = sealed("correct horse");
+        assert!(matches!(
+            sealed.open("battery staple"),
+            Err(KeystoreError::WrongPassword)
+        ));
+    }
+
+    #[test]
+    fn open_rejects_a_tampered_mac() {
+        let (_, mut sealed) = sealed("correct horse");
+        let mut mac = hex::decode(&sealed.mac).unwrap();
+        mac[0] ^= 1;
+        sealed.mac = hex::encode(mac);
+        assert!(matches!(
+            sealed.open("correct horse"),
+            Err(KeystoreError::WrongPassword)
+        ));
+    }
+
+    #[test]
+    fn open_rejects_a_tampered_ciphertext() {
+        let (_, mut sealed) = sealed("correct horse");
+        let mut ciphertext = hex::decode(&sealed.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        sealed.ciphertext = hex::encode(ciphertext);
+        assert!(matches!(
+            sealed.open("correct horse"),
+            Err(KeystoreError::WrongPassword)
+        ));
+    }
+
+    #[test]
+    fn open_rejects_out_of_range_scrypt_params() {
+        let (_, sealed) = sealed("correct horse");
+        let costly = [
+            KdfParams {
+                log_n: 40,
+                ..TEST_KDF
+            },
+            KdfParams {
+                r: 1 << 20,
+                ..TEST_KDF
+            },
+            KdfParams {
+                p: 1 << 20,
+                ..TEST_KDF
+            },
+            KdfParams {
+                log_n: 0,
+                ..TEST_KDF
+            },
+        ];
+        for kdf in costly {
+            let sealed = EncryptedSecret {
+                kdf,
+                ..sealed.clone()
+            };
+            assert!(matches!(
+                sealed.open("correct horse"),
+                Err(KeystoreError::InvalidKdfParams)
+            ));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn save_leaves_the_file_to_its_owner() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!(
+            "rustchain-test-{}.keystore.json",
+            uuid::Uuid::new_v4()
+        ));
+        let path = path.to_str().unwrap();
+        // An existing world readable file is replaced by an owner only one
+        std::fs::write(path, "").unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        let keystore = Keystore {
+            path: path.to_string(),
+            version: KEYSTORE_VERSION,
+            keys: Vec::new(),
+        };
+        keystore.save().unwrap();
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn save_replaces_the_keystore_without_leftovers() {
+        let dir = std::env::temp_dir().join(format!("rustchain-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("wallet.keystore.json");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "not a keystore").unwrap();
+
+        let keystore = Keystore {
+            path: path.to_string(),
+            version: KEYSTORE_VERSION,
+            keys: Vec::new(),
+        };
+        keystore.save().unwrap();
+        let loaded = Keystore::load(path);
+        let files = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(loaded.unwrap().keys().is_empty());
+        assert_eq!(files, 1);
+    }
+}
@@ -0,0 +1,44 @@
+//! Local wallet: Ed25519 key pairs, the addresses derived from them and the
+//! password encrypted keystore they are kept in.
+
+pub mod keystore;
+
+pub use keystore::Keystore;
+
+use ed25519_dalek::VerifyingKey;
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::Path;
+
+/// Environment variable read for the keystore password before prompting.
+pub const PASSWORD_ENV: &str = "RUSTCHAIN_WALLET_PASSWORD";
+
+/// Keystore of the wallet commands when there is no chain file to keep it next to.
+pub const DEFAULT_KEYSTORE: &str = "wallet.keystore.json";
+
+// Bytes of the public key hash making up an address
+const ADDRESS_LEN: usize = 20;
+
+/// Address of a public key, the hex of the first 20 bytes of its SHA-256.
+pub fn address(public_key: &VerifyingKey) -> String {
+    let digest = Sha256::digest(public_key.to_bytes());
+    hex::encode(&digest[..ADDRESS_LEN])
+}
+
+/// Keystore kept next to `chain_file`, `chain_8080.json` has its keys in
+/// `chain_8080.keystore.json`.
+pub fn keystore_path(chain_file: &str) -> String {
+    Path::new(chain_file)
+        .with_extension("keystore.json")
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Password from `PASSWORD_ENV`, or else read from the terminal without
+/// echoing it after showing `prompt`.
+pub fn read_password(prompt: &str) -> io::Result<String> {
+    if let Ok(password) = std::env::var(PASSWORD_ENV) {
+        return Ok(password);
+    }
+    rpassword::prompt_password(prompt)
+}